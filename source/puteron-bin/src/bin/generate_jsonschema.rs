@@ -2,6 +2,7 @@ use {
     puteron::interface::{
         self,
         demon::Config,
+        ipc::IpcError,
         task::Task,
     },
     schemars::schema_for,
@@ -20,6 +21,7 @@ fn main() {
     create_dir_all(&root).unwrap();
     write(root.join("task.schema.json"), serde_json::to_vec_pretty(&schema_for!(Task)).unwrap()).unwrap();
     write(root.join("config.schema.json"), serde_json::to_vec_pretty(&schema_for!(Config)).unwrap()).unwrap();
+    write(root.join("ipc_error.schema.json"), serde_json::to_vec_pretty(&schema_for!(IpcError)).unwrap()).unwrap();
     for (k, v) in interface::ipc::ipc::to_json_schema() {
         write(root.join(format!("ipc_{}.schema.json", k)), serde_json::to_vec_pretty(&v).unwrap()).unwrap();
     }
@@ -1,8 +1,20 @@
 use {
     aargvark::{
-        traits_impls::AargvarkJson,
+        help::{
+            HelpPattern,
+            HelpPatternElement,
+            HelpState,
+        },
+        traits_impls::{
+            AargvarkFromStr,
+            AargvarkJson,
+        },
         Aargvark,
     },
+    chrono::{
+        Local,
+        Utc,
+    },
     flowcontrol::ta_return,
     loga::{
         ea,
@@ -10,26 +22,44 @@ use {
         Log,
         ResultContext,
     },
-    puteron::interface::{
-        self,
-        base::TaskId,
-        ipc::{
-            ProcState,
-            RequestDemonEnv,
-            RequestDemonSpecDirs,
-            RequestTaskAdd,
-            RequestTaskDelete,
-            RequestTaskGetSpec,
-            RequestTaskGetStatus,
-            RequestTaskList,
-            RequestTaskListDownstream,
-            RequestTaskListUpstream,
-            RequestTaskListUserOn,
-            RequestTaskOnOff,
-            RequestTaskWaitStarted,
-            RequestTaskWaitStopped,
-            TaskStatusSpecific,
+    puteron::{
+        interface::{
+            self,
+            base::TaskId,
+            ipc::{
+                BatchOperation,
+                GraphEdge,
+                GraphNode,
+                GraphNodeType,
+                IpcError,
+                ProcState,
+                RequestBatch,
+                RequestDemonAuditLog,
+                RequestDemonEnv,
+                RequestDemonListSchedule,
+                RequestDemonSpecDirs,
+                RequestTaskAdd,
+                RequestTaskDelete,
+                RequestTaskGetSpec,
+                RequestTaskGetStatus,
+                RequestTaskGraph,
+                RequestTaskList,
+                RequestTaskListDownstream,
+                RequestTaskListUpstream,
+                RequestTaskListUserOn,
+                RequestTaskOnOff,
+                RequestTaskPlan,
+                RequestTaskReload,
+                RequestTaskRestart,
+                RequestTaskRunNow,
+                RequestTaskWait,
+                RequestTaskWhy,
+                RespTaskGraph,
+                TaskStatusSpecific,
+            },
+            task::DependencyType,
         },
+        time::SimpleDuration,
     },
     puteron_bin::{
         demon::{
@@ -38,13 +68,20 @@ use {
         },
         ipc_util::{
             client,
+            client_error,
             client_req,
+            ClientError,
+        },
+        spec::{
+            build_spec_graph,
+            merge_specs,
         },
-        spec::merge_specs,
     },
     serde::Serialize,
-    std::collections::HashMap,
-    tokio::runtime,
+    std::{
+        collections::HashMap,
+        path::PathBuf,
+    },
 };
 
 #[derive(Aargvark)]
@@ -55,6 +92,117 @@ pub struct LoadArgs {
     spec: AargvarkJson<interface::task::Task>,
     /// Error if a task with the specification already exists.
     unique: Option<()>,
+    /// If the task is running, stop it and its downstream tasks, replace it, and
+    /// start everything again.
+    restart: Option<()>,
+}
+
+#[derive(Aargvark)]
+pub struct LoadStoredArgs {
+    /// ID of task to load.
+    task: TaskId,
+    /// If the task is running, stop it and its downstream tasks, replace it, and
+    /// start everything again.
+    restart: Option<()>,
+}
+
+#[derive(Aargvark)]
+pub struct RunNowArgs {
+    /// Short task to run.
+    task: TaskId,
+    /// Recalculate the task's next scheduled times relative to now.
+    reset_schedule: Option<()>,
+}
+
+#[derive(Aargvark)]
+pub struct ReloadTaskArgs {
+    /// Long task to reload.
+    task: TaskId,
+    /// Wait for the task's started check to pass again.
+    wait: Option<()>,
+}
+
+/// A `SimpleDuration` as a command line argument, like `30s` or `5m`.
+pub struct ArgDuration(SimpleDuration);
+
+impl AargvarkFromStr for ArgDuration {
+    fn from_str(s: &str) -> Result<Self, String> {
+        return Ok(ArgDuration(s.parse()?));
+    }
+
+    fn build_help_pattern(_state: &mut HelpState) -> HelpPattern {
+        return HelpPattern(vec![HelpPatternElement::Type("DURATION".to_string())]);
+    }
+}
+
+#[derive(Aargvark)]
+pub struct WaitArgs {
+    /// Tasks to wait for.
+    tasks: Vec<TaskId>,
+    /// Exit once any of the tasks is in the state, rather than all of them.
+    any: Option<()>,
+    /// Give up after this long (ex: `30s`, `5m`), printing the state of each task.
+    timeout: Option<ArgDuration>,
+}
+
+#[derive(Aargvark)]
+enum WaitState {
+    Stopped,
+    Starting,
+    Started,
+    Stopping,
+}
+
+#[derive(Aargvark)]
+pub struct WaitStateArgs {
+    /// State to wait for.
+    state: WaitState,
+    /// Tasks to wait for.
+    tasks: Vec<TaskId>,
+    /// Exit once any of the tasks is in the state, rather than all of them.
+    any: Option<()>,
+    /// Give up after this long (ex: `30s`, `5m`), printing the state of each task.
+    timeout: Option<ArgDuration>,
+}
+
+#[derive(Aargvark)]
+pub struct ListScheduleArgs {
+    /// Only show entries for this task.
+    task: Option<TaskId>,
+    /// Show at most this many entries.
+    limit: Option<usize>,
+}
+
+#[derive(Aargvark)]
+pub struct AuditLogArgs {
+    /// Only show entries for this task.
+    task: Option<TaskId>,
+    /// Show at most this many entries (the most recent).
+    limit: Option<usize>,
+}
+
+#[derive(Aargvark)]
+enum GraphFormat {
+    Json,
+    Dot,
+    Mermaid,
+}
+
+#[derive(Aargvark)]
+pub struct GraphArgs {
+    /// Output format, defaults to `json`.
+    format: Option<GraphFormat>,
+    /// Build the graph from the specs in these task directories rather than asking
+    /// the running demon. Nodes won't have on or state information.
+    task_dirs: Option<Vec<PathBuf>>,
+}
+
+#[derive(Aargvark)]
+enum PlanCommand {
+    /// Show what turning a task on would do.
+    On(TaskId),
+    /// Show what turning a task off would do.
+    Off(TaskId),
 }
 
 #[derive(Aargvark)]
@@ -65,7 +213,7 @@ enum ArgCommand {
     Load(LoadArgs),
     /// Load or replace a task with the specified id from the demon task configuration
     /// directories.
-    LoadStored(TaskId),
+    LoadStored(LoadStoredArgs),
     /// Show the merged spec for a task from the demon task configuration directories,
     /// as it would be loaded.
     PreviewStored(TaskId),
@@ -73,6 +221,9 @@ enum ArgCommand {
     ///
     /// No error if the task is already deleted.
     Delete(TaskId),
+    /// Add, delete, and turn on and off multiple tasks at once from a JSON list of
+    /// operations. If any operation fails nothing is changed.
+    Batch(AargvarkJson<Vec<BatchOperation>>),
     /// Get various runtime info about a task.
     Status(TaskId),
     /// Get the merged loaded spec for a task.
@@ -85,16 +236,44 @@ enum ArgCommand {
     ///
     /// No error if the task is already off.
     Off(TaskId),
-    /// Wait for a task to start.
+    /// Run a short task now, as if its schedule had triggered it. Once the run
+    /// completes the task is turned off again (unless it has a different started
+    /// action). Fails if the task is already on or still running.
+    ///
+    /// The task's schedule is unaffected unless `--reset-schedule` is specified.
+    RunNow(RunNowArgs),
+    /// Stop a task and its downstream tasks and start them again. Whether the tasks
+    /// are on is unchanged, so this does nothing if the task isn't on.
+    Restart(TaskId),
+    /// Send a long task its `reload_signal` or run its `reload_command`, without
+    /// stopping it.
     ///
-    /// Exits immediately if the task has already started. Exits with an error if the
+    /// With `--wait`, exits once the task's started check passes (which is usually
+    /// right away, unless the process closes its listener or removes its check file
+    /// while reloading).
+    ReloadTask(ReloadTaskArgs),
+    /// Wait for tasks to start.
+    ///
+    /// Exits immediately if the tasks have already started. Exits with an error if a
     /// task is turned off.
-    WaitUntilStarted(TaskId),
-    /// Wait for a task to stop.
+    WaitUntilStarted(WaitArgs),
+    /// Wait for tasks to stop.
     ///
-    /// Exits immediately if the task has already stopped. Exits with an error if the
+    /// Exits immediately if the tasks have already stopped. Exits with an error if a
     /// task is turned on.
-    WaitUntilStopped(TaskId),
+    WaitUntilStopped(WaitArgs),
+    /// Wait for tasks to be in a state. Tasks without a process are only ever
+    /// `started` or `stopped`.
+    WaitUntilState(WaitStateArgs),
+    /// Show which tasks would be started and stopped by turning a task on or off,
+    /// without changing anything.
+    Plan(PlanCommand),
+    /// Explain why a task is on or off, and if it's on but not started, what it's
+    /// waiting for.
+    Why(TaskId),
+    /// Dump the task dependency graph. Edges point from downstream tasks to their
+    /// upstreams.
+    Graph(GraphArgs),
     /// List tasks that are user-on.
     ListUserOn,
     /// List tasks upstream of a task, plus their control and current states.
@@ -105,7 +284,10 @@ enum ArgCommand {
     Env,
     /// List the current schedule. This includes the next time of all scheduled tasks.
     /// The schedule is in ascending scheduled activation time.
-    ListSchedule,
+    ListSchedule(ListScheduleArgs),
+    /// Show recent changes to tasks' on state, with what made them (ipc client uid
+    /// and pid, schedule rule, etc). Oldest first.
+    AuditLog(AuditLogArgs),
     /// Run the demon in the foreground.
     Demon(DemonRunArgs),
 }
@@ -117,6 +299,174 @@ struct Args {
     debug: Option<()>,
 }
 
+/// Format a duration like `in 3h12m`, with the two most significant units.
+fn format_relative(d: chrono::Duration) -> String {
+    let mut secs = d.num_seconds();
+    if secs <= 0 {
+        return "now".to_string();
+    }
+    let mut parts = vec![];
+    for (unit_secs, suffix) in [(60 * 60 * 24, "d"), (60 * 60, "h"), (60, "m"), (1, "s")] {
+        let count = secs / unit_secs;
+        secs -= count * unit_secs;
+        if count > 0 || !parts.is_empty() {
+            parts.push(format!("{}{}", count, suffix));
+        }
+    }
+    parts.truncate(2);
+    if parts.len() == 2 && parts[1].starts_with('0') {
+        parts.pop();
+    }
+    return format!("in {}", parts.join(""));
+}
+
+fn graph_node_label(id: &TaskId, node: &GraphNode) -> String {
+    let mut parts = vec![match node.task_type {
+        GraphNodeType::Empty => "empty",
+        GraphNodeType::Long => "long",
+        GraphNodeType::Short => "short",
+    }];
+    if let Some(on) = node.on {
+        parts.push(match on {
+            true => "on",
+            false => "off",
+        });
+    }
+    if let Some(state) = node.state {
+        parts.push(match state {
+            ProcState::Stopped => "stopped",
+            ProcState::Starting => "starting",
+            ProcState::Started => "started",
+            ProcState::Stopping => "stopping",
+        });
+    }
+    return format!("{}\n{}", id, parts.join(", "));
+}
+
+fn graph_edge_label(edge: &GraphEdge) -> String {
+    let dependency_type = match edge.dependency_type {
+        DependencyType::Strong => "strong",
+        DependencyType::Weak => "weak",
+        DependencyType::After => "after",
+        DependencyType::Conflicts => "conflicts",
+    };
+    match &edge.group {
+        Some(group) => return format!("{} (any {})", dependency_type, group),
+        None => return dependency_type.to_string(),
+    }
+}
+
+/// Quote a string for DOT. Only `"` is escaped in DOT strings, but a backslash
+/// starts an escape in labels (ex: `\n`) so those are doubled too.
+fn dot_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    return out;
+}
+
+fn format_graph_dot(graph: &RespTaskGraph) -> String {
+    let mut out = vec!["digraph puteron {".to_string()];
+    for (id, node) in &graph.nodes {
+        out.push(format!("    {} [label={}];", dot_string(id), dot_string(&graph_node_label(id, node))));
+    }
+    for edge in &graph.edges {
+        let style = match edge.dependency_type {
+            DependencyType::Strong => "solid",
+            DependencyType::Weak => "dashed",
+            DependencyType::After => "dotted",
+            DependencyType::Conflicts => "bold",
+        };
+        out.push(
+            format!(
+                "    {} -> {} [label={}, style={}];",
+                dot_string(&edge.downstream),
+                dot_string(&edge.upstream),
+                dot_string(&graph_edge_label(edge)),
+                style
+            ),
+        );
+    }
+    out.push("}".to_string());
+    return out.join("\n");
+}
+
+fn format_graph_mermaid(graph: &RespTaskGraph) -> String {
+    // Task ids can contain characters mermaid doesn't allow in node ids, so number
+    // them
+    let mut ids = HashMap::new();
+    let mut out = vec!["flowchart TD".to_string()];
+    for (id, node) in &graph.nodes {
+        let mermaid_id = format!("n{}", ids.len());
+        out.push(format!("    {}[\"{}\"]", mermaid_id, graph_node_label(id, node).replace("\n", "<br>")));
+        ids.insert(id.clone(), mermaid_id);
+    }
+    for edge in &graph.edges {
+        for id in [&edge.downstream, &edge.upstream] {
+            if !ids.contains_key(id) {
+                // Missing task
+                let mermaid_id = format!("n{}", ids.len());
+                out.push(format!("    {}[\"{}\"]", mermaid_id, id));
+                ids.insert(id.clone(), mermaid_id);
+            }
+        }
+        let arrow = match edge.dependency_type {
+            DependencyType::Strong => "-->",
+            DependencyType::Weak => "-.->",
+            DependencyType::After => "--o",
+            DependencyType::Conflicts => "---",
+        };
+        out.push(
+            format!(
+                "    {} {}|{}| {}",
+                ids.get(&edge.downstream).unwrap(),
+                arrow,
+                graph_edge_label(edge),
+                ids.get(&edge.upstream).unwrap()
+            ),
+        );
+    }
+    return out.join("\n");
+}
+
+fn wait_request(args: WaitArgs, state: ProcState) -> RequestTaskWait {
+    return RequestTaskWait {
+        tasks: args.tasks,
+        state: state,
+        any: args.any.is_some(),
+        timeout: args.timeout.map(|x| x.0),
+    };
+}
+
+/// Exit codes for errors returned by the demon, so scripts can tell them apart.
+/// Other errors exit with 1.
+fn ipc_error_exit_code(e: &IpcError) -> i32 {
+    match e {
+        IpcError::UnknownTask { .. } => return 10,
+        IpcError::TaskExists { .. } => return 11,
+        IpcError::TaskNotStopped { .. } => return 12,
+        IpcError::NotShortTask { .. } => return 13,
+        IpcError::InvalidSpec { .. } => return 14,
+        IpcError::StartCanceled { .. } => return 15,
+        IpcError::StopCanceled { .. } => return 16,
+        IpcError::PermissionDenied { .. } => return 17,
+        IpcError::InvalidBatch { .. } => return 18,
+        IpcError::TaskNotStarted { .. } => return 19,
+        IpcError::NotReloadable { .. } => return 20,
+        IpcError::ReloadFailed { .. } => return 21,
+        IpcError::WaitTimeout { .. } => return 22,
+        IpcError::InvalidRequest { .. } => return 23,
+        IpcError::Internal { .. } => return 1,
+    }
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
     let args = aargvark::vark::<Args>();
@@ -125,10 +475,11 @@ async fn main() {
         false => loga::INFO,
     });
     match async {
+        ta_return!((), ClientError);
         match args.command {
             ArgCommand::Overview => {
                 let mut client = client().await?;
-                let tasks = client.send_req(RequestTaskList).await.map_err(loga::err)?;
+                let tasks = client.send_req(RequestTaskList).await.map_err(client_error)?;
 
                 #[derive(Serialize)]
                 struct Entry {
@@ -138,7 +489,7 @@ async fn main() {
 
                 let mut out = HashMap::new();
                 for task in tasks {
-                    let status = client.send_req(RequestTaskGetStatus(task.clone())).await.map_err(loga::err)?;
+                    let status = client.send_req(RequestTaskGetStatus(task.clone())).await.map_err(client_error)?;
                     const STATE_STARTING: &str = "starting";
                     const STATE_STARTED: &str = "started";
                     const STATE_STOPPING: &str = "stopping";
@@ -172,19 +523,21 @@ async fn main() {
                     task: args.task,
                     spec: args.spec.value,
                     unique: args.unique.is_some(),
+                    restart: args.restart.is_some(),
                 }).await?;
             },
-            ArgCommand::LoadStored(task_id) => {
+            ArgCommand::LoadStored(args) => {
                 let dirs = client_req(RequestDemonSpecDirs {}).await?;
                 let spec =
-                    merge_specs(&log, &dirs, Some(&task_id))
+                    merge_specs(&log, &dirs, Some(&args.task))
                         .await?
-                        .remove(&task_id)
-                        .context_with("Found no specs for task", ea!(task = task_id))?;
+                        .remove(&args.task)
+                        .context_with("Found no specs for task", ea!(task = args.task))?;
                 client_req(RequestTaskAdd {
-                    task: task_id,
+                    task: args.task,
                     spec,
                     unique: false,
+                    restart: args.restart.is_some(),
                 }).await?;
             },
             ArgCommand::PreviewStored(task_id) => {
@@ -199,6 +552,9 @@ async fn main() {
             ArgCommand::Delete(task_id) => {
                 client_req(RequestTaskDelete(task_id)).await?;
             },
+            ArgCommand::Batch(operations) => {
+                client_req(RequestBatch { operations: operations.value }).await?;
+            },
             ArgCommand::Status(task_id) => {
                 let status = client_req(RequestTaskGetStatus(task_id)).await?;
                 println!("{}", serde_json::to_string_pretty(&status).unwrap());
@@ -219,11 +575,66 @@ async fn main() {
                     on: false,
                 }).await?;
             },
-            ArgCommand::WaitUntilStarted(task_id) => {
-                client_req(RequestTaskWaitStarted(task_id)).await?;
+            ArgCommand::RunNow(args) => {
+                client_req(RequestTaskRunNow {
+                    task: args.task,
+                    reset_schedule: args.reset_schedule.is_some(),
+                }).await?;
+            },
+            ArgCommand::Restart(task_id) => {
+                client_req(RequestTaskRestart(task_id)).await?;
+            },
+            ArgCommand::ReloadTask(args) => {
+                client_req(RequestTaskReload {
+                    task: args.task,
+                    wait: args.wait.is_some(),
+                }).await?;
+            },
+            ArgCommand::WaitUntilStarted(args) => {
+                client_req(wait_request(args, ProcState::Started)).await?;
             },
-            ArgCommand::WaitUntilStopped(task_id) => {
-                client_req(RequestTaskWaitStopped(task_id)).await?;
+            ArgCommand::WaitUntilStopped(args) => {
+                client_req(wait_request(args, ProcState::Stopped)).await?;
+            },
+            ArgCommand::WaitUntilState(args) => {
+                let state = match args.state {
+                    WaitState::Stopped => ProcState::Stopped,
+                    WaitState::Starting => ProcState::Starting,
+                    WaitState::Started => ProcState::Started,
+                    WaitState::Stopping => ProcState::Stopping,
+                };
+                client_req(wait_request(WaitArgs {
+                    tasks: args.tasks,
+                    any: args.any,
+                    timeout: args.timeout,
+                }, state)).await?;
+            },
+            ArgCommand::Plan(args) => {
+                let (task_id, on) = match args {
+                    PlanCommand::On(task_id) => (task_id, true),
+                    PlanCommand::Off(task_id) => (task_id, false),
+                };
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&client_req(RequestTaskPlan {
+                        task: task_id,
+                        on: on,
+                    }).await?).unwrap()
+                );
+            },
+            ArgCommand::Why(task_id) => {
+                println!("{}", serde_json::to_string_pretty(&client_req(RequestTaskWhy(task_id)).await?).unwrap());
+            },
+            ArgCommand::Graph(args) => {
+                let graph = match args.task_dirs {
+                    Some(dirs) => build_spec_graph(&merge_specs(&log, &dirs, None).await?),
+                    None => client_req(RequestTaskGraph).await?,
+                };
+                match args.format.unwrap_or(GraphFormat::Json) {
+                    GraphFormat::Json => println!("{}", serde_json::to_string_pretty(&graph).unwrap()),
+                    GraphFormat::Dot => println!("{}", format_graph_dot(&graph)),
+                    GraphFormat::Mermaid => println!("{}", format_graph_mermaid(&graph)),
+                }
             },
             ArgCommand::ListUserOn => {
                 println!("{}", serde_json::to_string_pretty(&client_req(RequestTaskListUserOn).await?).unwrap());
@@ -241,30 +652,46 @@ async fn main() {
                 );
             },
             ArgCommand::Env => {
-                let rt =
-                    runtime::Builder::new_current_thread()
-                        .enable_all()
-                        .build()
-                        .context("Error starting async runtime")?;
-                return rt.block_on(async move {
-                    ta_return!((), loga::Error);
-                    let status = client_req(RequestDemonEnv).await?;
-                    println!("{}", serde_json::to_string_pretty(&status).unwrap());
-                    return Ok(());
-                });
+                let status = client_req(RequestDemonEnv).await?;
+                println!("{}", serde_json::to_string_pretty(&status).unwrap());
             },
-            ArgCommand::ListSchedule => {
-                let rt =
-                    runtime::Builder::new_current_thread()
-                        .enable_all()
-                        .build()
-                        .context("Error starting async runtime")?;
-                return rt.block_on(async move {
-                    ta_return!((), loga::Error);
-                    let status = client_req(RequestDemonEnv).await?;
-                    println!("{}", serde_json::to_string_pretty(&status).unwrap());
-                    return Ok(());
-                });
+            ArgCommand::ListSchedule(args) => {
+                let entries = client_req(RequestDemonListSchedule {
+                    task: args.task,
+                    limit: args.limit,
+                }).await?;
+
+                #[derive(Serialize)]
+                struct Entry {
+                    task: TaskId,
+                    at: String,
+                    relative: String,
+                    action: &'static str,
+                    rule: String,
+                }
+
+                let now = Utc::now();
+                let mut out = vec![];
+                for entry in entries {
+                    out.push(Entry {
+                        task: entry.task,
+                        at: entry.at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S %:z").to_string(),
+                        relative: format_relative(entry.at - now),
+                        action: match entry.on {
+                            true => "on",
+                            false => "off",
+                        },
+                        rule: entry.rule.to_string(),
+                    });
+                }
+                println!("{}", serde_json::to_string_pretty(&out).unwrap());
+            },
+            ArgCommand::AuditLog(args) => {
+                let entries = client_req(RequestDemonAuditLog {
+                    task: args.task,
+                    limit: args.limit,
+                }).await?;
+                println!("{}", serde_json::to_string_pretty(&entries).unwrap());
             },
             ArgCommand::Demon(args) => {
                 demon::main(&log, args).await?;
@@ -273,7 +700,11 @@ async fn main() {
         return Ok(());
     }.await {
         Ok(_) => { },
-        Err(e) => {
+        Err(ClientError::Ipc(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(ipc_error_exit_code(&e));
+        },
+        Err(ClientError::Other(e)) => {
             fatal(e);
         },
     }
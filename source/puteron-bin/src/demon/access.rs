@@ -0,0 +1,198 @@
+use {
+    super::{
+        state::State,
+        task_util::{
+            get_task_allow_control,
+            maybe_get_task,
+        },
+    },
+    crate::ipc_util::PeerCredentials,
+    loga::{
+        ea,
+        DebugDisplay,
+        ErrContext,
+    },
+    puteron::interface::{
+        base::{
+            AccessList,
+            TaskId,
+        },
+        ipc::{
+            ipc,
+            BatchOperation,
+        },
+    },
+    std::{
+        collections::HashMap,
+        path::Path,
+    },
+};
+
+/// Each class also allows everything in the classes before it.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum AccessClass {
+    Read,
+    Control,
+    Manage,
+}
+
+pub(crate) fn access_class_name(class: AccessClass) -> &'static str {
+    match class {
+        AccessClass::Read => return "read",
+        AccessClass::Control => return "control",
+        AccessClass::Manage => return "manage",
+    }
+}
+
+/// The tasks a request controls, for requests that can be allowed by the tasks'
+/// `allow_control`. Empty for other requests.
+pub(crate) fn get_controlled_tasks(req: &ipc::ServerReq) -> Vec<&TaskId> {
+    match req {
+        ipc::ServerReq::TaskOnOff(_, m) => return vec![&m.task],
+        ipc::ServerReq::TaskRunNow(_, m) => return vec![&m.task],
+        ipc::ServerReq::TaskRestart(_, m) => return vec![&m.0],
+        ipc::ServerReq::TaskReload(_, m) => return vec![&m.task],
+        ipc::ServerReq::Batch(_, m) => {
+            let mut out = vec![];
+            for op in &m.operations {
+                match op {
+                    BatchOperation::Add(_) | BatchOperation::Delete(_) => return vec![],
+                    BatchOperation::OnOff(op) => out.push(&op.task),
+                }
+            }
+            return out;
+        },
+        _ => return vec![],
+    }
+}
+
+pub(crate) fn get_access_class(req: &ipc::ServerReq) -> AccessClass {
+    match req {
+        ipc::ServerReq::TaskList(..) => return AccessClass::Read,
+        ipc::ServerReq::TaskAdd(..) => return AccessClass::Manage,
+        ipc::ServerReq::TaskDelete(..) => return AccessClass::Manage,
+        ipc::ServerReq::Batch(_, m) => {
+            let mut out = AccessClass::Control;
+            for op in &m.operations {
+                match op {
+                    BatchOperation::Add(_) | BatchOperation::Delete(_) => out = AccessClass::Manage,
+                    BatchOperation::OnOff(_) => { },
+                }
+            }
+            return out;
+        },
+        ipc::ServerReq::TaskGetStatus(..) => return AccessClass::Read,
+        // Specs may have secrets in environment values
+        ipc::ServerReq::TaskGetSpec(..) => return AccessClass::Manage,
+        ipc::ServerReq::TaskOnOff(..) => return AccessClass::Control,
+        ipc::ServerReq::TaskRunNow(..) => return AccessClass::Control,
+        ipc::ServerReq::TaskRestart(..) => return AccessClass::Control,
+        ipc::ServerReq::TaskReload(..) => return AccessClass::Control,
+        ipc::ServerReq::TaskWaitStarted(..) => return AccessClass::Read,
+        ipc::ServerReq::TaskWaitStopped(..) => return AccessClass::Read,
+        ipc::ServerReq::TaskWait(..) => return AccessClass::Read,
+        ipc::ServerReq::TaskListUserOn(..) => return AccessClass::Read,
+        ipc::ServerReq::TaskListUpstream(..) => return AccessClass::Read,
+        ipc::ServerReq::TaskListDownstream(..) => return AccessClass::Read,
+        ipc::ServerReq::TaskPlan(..) => return AccessClass::Read,
+        ipc::ServerReq::TaskWhy(..) => return AccessClass::Read,
+        ipc::ServerReq::TaskGraph(..) => return AccessClass::Read,
+        // The demon environment may have secrets inherited from the parent process
+        ipc::ServerReq::DemonEnv(..) => return AccessClass::Manage,
+        ipc::ServerReq::DemonListSchedule(..) => return AccessClass::Read,
+        ipc::ServerReq::DemonAuditLog(..) => return AccessClass::Read,
+        ipc::ServerReq::DemonSpecDirs(..) => return AccessClass::Read,
+    }
+}
+
+/// Read names and ids from a `/etc/passwd` or `/etc/group` style file. A missing
+/// file is treated as empty.
+pub(crate) async fn load_id_names(path: &Path) -> Result<HashMap<String, u32>, loga::Error> {
+    let text = match tokio::fs::read_to_string(path).await {
+        Ok(t) => t,
+        Err(e) => {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                return Ok(HashMap::new());
+            }
+            return Err(e.context_with("Error reading id names", ea!(path = path.dbg_str())));
+        },
+    };
+    let mut out = HashMap::new();
+    for line in text.lines() {
+        let mut fields = line.split(':');
+        let (Some(name), Some(_), Some(id)) = (fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        let Ok(id) = id.parse::<u32>() else {
+            continue;
+        };
+        out.entry(name.to_string()).or_insert(id);
+    }
+    return Ok(out);
+}
+
+/// User and group names in the list that don't exist, for error messages.
+pub(crate) fn get_unknown_access_names(state: &State, list: &AccessList) -> Vec<String> {
+    let mut out = vec![];
+    for name in &list.users {
+        if !state.user_ids.contains_key(name) {
+            out.push(format!("user [{}]", name));
+        }
+    }
+    for name in &list.groups {
+        if !state.group_ids.contains_key(name) {
+            out.push(format!("group [{}]", name));
+        }
+    }
+    return out;
+}
+
+pub(crate) fn is_in_access_list(state: &State, list: &AccessList, peer: &PeerCredentials) -> bool {
+    if list.uids.contains(&peer.uid) || list.users.iter().any(|n| state.user_ids.get(n) == Some(&peer.uid)) {
+        return true;
+    }
+    for gid in [peer.gid].iter().chain(&peer.groups) {
+        if list.gids.contains(gid) || list.groups.iter().any(|n| state.group_ids.get(n) == Some(gid)) {
+            return true;
+        }
+    }
+    return false;
+}
+
+/// Everything is allowed if access isn't configured. Otherwise root and the demon's
+/// user can do everything, clients whose credentials couldn't be determined can do
+/// nothing, and other clients are checked against the access lists.
+pub(crate) fn is_access_allowed(state: &State, peer: Option<&PeerCredentials>, class: AccessClass) -> bool {
+    let Some(access) = &state.access else {
+        return true;
+    };
+    let Some(peer) = peer else {
+        return false;
+    };
+    if peer.uid == 0 || peer.uid == rustix::process::getuid().as_raw() {
+        return true;
+    }
+    for (list_class, list) in [
+        (AccessClass::Read, &access.read),
+        (AccessClass::Control, &access.control),
+        (AccessClass::Manage, &access.manage),
+    ] {
+        if list_class >= class && is_in_access_list(state, list, peer) {
+            return true;
+        }
+    }
+    return false;
+}
+
+/// Check the task's `allow_control`. Only existing tasks can be controlled this
+/// way, so new template instances can't be created.
+pub(crate) fn is_task_control_allowed(state: &State, peer: Option<&PeerCredentials>, task_id: &TaskId) -> bool {
+    let Some(peer) = peer else {
+        return false;
+    };
+    let state_dynamic = state.dynamic.lock().unwrap();
+    let Some(task) = maybe_get_task(&state_dynamic, task_id) else {
+        return false;
+    };
+    return is_in_access_list(state, get_task_allow_control(task), peer);
+}
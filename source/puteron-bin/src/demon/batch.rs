@@ -0,0 +1,369 @@
+use {
+    super::{
+        schedule::is_in_schedule_window,
+        state::{
+            State,
+            StateDynamic,
+        },
+        task_create_delete::{
+            build_task,
+            delete_task,
+            delete_unused_instances,
+            instantiate_task,
+            remove_task,
+            restore_task,
+            validate_new_task,
+            validate_new_template,
+            validate_task_graph,
+        },
+        task_execute::{
+            execute_user_plan,
+            plan_task_user_off,
+            plan_task_user_on,
+        },
+        task_plan::ExecutePlan,
+        task_util::{
+            get_missing_upstream,
+            get_spec_upstream,
+            get_task,
+            is_same_task_spec,
+            is_task_stopped,
+            maybe_get_task,
+        },
+    },
+    puteron::interface::{
+        base::{
+            is_template_id,
+            split_instance_id,
+            TaskId,
+        },
+        ipc::{
+            BatchOperation,
+            ControlReason,
+            ControlSource,
+            IpcError,
+        },
+        task::{
+            DependencyType,
+            ShortTaskStartedAction,
+            Task,
+        },
+    },
+    std::{
+        collections::{
+            BTreeMap,
+            BTreeSet,
+            HashSet,
+        },
+        sync::Arc,
+    },
+};
+
+fn invalid_batch(missing_upstream: BTreeSet<TaskId>, errors: Vec<loga::Error>) -> IpcError {
+    return IpcError::InvalidBatch {
+        missing_upstream: missing_upstream.into_iter().collect(),
+        errors: errors.into_iter().map(|x| x.to_string()).collect(),
+    };
+}
+
+/// Apply the operations of a `RequestBatch`. Replaced and deleted tasks are
+/// removed, the new tasks are created, and if anything fails the removed tasks
+/// are put back. On/off changes are then planned and executed together.
+pub(crate) fn apply_batch(
+    state: &Arc<State>,
+    state_dynamic: &mut StateDynamic,
+    operations: Vec<BatchOperation>,
+    user_reason: impl Fn(ControlSource) -> ControlReason,
+) -> Result<(), IpcError> {
+    // # Sort operations
+    let mut errors = vec![];
+    let mut add = BTreeMap::new();
+    let mut add_templates = vec![];
+    let mut delete = BTreeSet::new();
+    let mut delete_templates = vec![];
+    let mut on_off = vec![];
+    let mut seen_add_delete = HashSet::new();
+    let mut seen_on_off = HashSet::new();
+    for op in operations {
+        match op {
+            BatchOperation::Add(op) => {
+                if !seen_add_delete.insert(op.task.clone()) {
+                    errors.push(loga::err(format!("Task [{}] is added or deleted more than once", op.task)));
+                    continue;
+                }
+                if op.restart {
+                    errors.push(loga::err(format!("Task [{}] can't be added with `restart` in a batch", op.task)));
+                    continue;
+                }
+                if is_template_id(&op.task) {
+                    if op.unique && state_dynamic.templates.contains_key(&op.task) {
+                        return Err(IpcError::TaskExists { task: op.task });
+                    }
+                    add_templates.push((op.task, op.spec));
+                    continue;
+                }
+                if let Some(task) = maybe_get_task(state_dynamic, &op.task) {
+                    if op.unique {
+                        return Err(IpcError::TaskExists { task: op.task });
+                    }
+                    if is_same_task_spec(task, &op.spec) {
+                        continue;
+                    }
+                }
+                add.insert(op.task, op.spec);
+            },
+            BatchOperation::Delete(op) => {
+                if !seen_add_delete.insert(op.0.clone()) {
+                    errors.push(loga::err(format!("Task [{}] is added or deleted more than once", op.0)));
+                    continue;
+                }
+                if is_template_id(&op.0) {
+                    delete_templates.push(op.0);
+                    continue;
+                }
+                if !state_dynamic.tasks.contains_key(&op.0) {
+                    continue;
+                }
+                delete.insert(op.0);
+            },
+            BatchOperation::OnOff(op) => {
+                if !seen_on_off.insert(op.task.clone()) {
+                    errors.push(loga::err(format!("Task [{}] is turned on or off more than once", op.task)));
+                    continue;
+                }
+                on_off.push(op);
+            },
+        }
+    }
+    if !errors.is_empty() {
+        return Err(invalid_batch(BTreeSet::new(), errors));
+    }
+
+    // # Check tasks that will be changed
+    let mut remove_ids = BTreeSet::new();
+    for task_id in add.keys().chain(delete.iter()) {
+        let Some(task) = maybe_get_task(state_dynamic, task_id) else {
+            continue;
+        };
+        if !is_task_stopped(task) {
+            return Err(IpcError::TaskNotStopped { task: task_id.clone() });
+        }
+        remove_ids.insert(task_id.clone());
+    }
+    for task_id in &delete {
+        for downstream_id in get_task(state_dynamic, task_id).downstream.borrow().keys() {
+            if !remove_ids.contains(downstream_id) {
+                errors.push(
+                    loga::err(format!("Task [{}] can't be deleted, task [{}] depends on it", task_id, downstream_id)),
+                );
+            }
+        }
+    }
+    for op in &on_off {
+        if delete.contains(&op.task) {
+            errors.push(loga::err(format!("Task [{}] is turned on or off but also deleted", op.task)));
+            continue;
+        }
+        if state_dynamic.tasks.contains_key(&op.task) || add.contains_key(&op.task) {
+            continue;
+        }
+        let template_exists = split_instance_id(&op.task).is_some_and(|(template_id, _)| {
+            state_dynamic.templates.contains_key(&template_id) ||
+                add_templates.iter().any(|(id, _)| *id == template_id)
+        });
+        if !op.on || !template_exists {
+            return Err(IpcError::UnknownTask { task: op.task.clone() });
+        }
+    }
+    if !errors.is_empty() {
+        return Err(invalid_batch(BTreeSet::new(), errors));
+    }
+
+    // # Remove replaced and deleted tasks, downstream first
+    //
+    // Conflicts aren't dependencies so they don't affect the order.
+    let mut removed = vec![];
+    while !remove_ids.is_empty() {
+        let mut did_work = false;
+        for task_id in remove_ids.iter().cloned().collect::<Vec<_>>() {
+            if get_task(state_dynamic, &task_id)
+                .downstream
+                .borrow()
+                .iter()
+                .any(|(x, t)| *t != DependencyType::Conflicts && remove_ids.contains(x)) {
+                continue;
+            }
+            did_work = true;
+            remove_ids.remove(&task_id);
+            removed.push(remove_task(state_dynamic, &task_id));
+        }
+        if !did_work {
+            errors.push(
+                loga::err(
+                    format!(
+                        "Tasks [{}] couldn't be ordered for removal",
+                        remove_ids.iter().cloned().collect::<Vec<_>>().join(", ")
+                    ),
+                ),
+            );
+            break;
+        }
+    }
+
+    // # Create new tasks, upstream first
+    let mut built = vec![];
+    let mut missing_upstream = BTreeSet::new();
+    if errors.is_empty() {
+        validate_task_graph(state_dynamic, &add, &mut errors);
+    }
+    if errors.is_empty() {
+        let mut remaining = add.clone();
+        while !remaining.is_empty() {
+            let mut did_work = false;
+            for task_id in remaining.keys().cloned().collect::<Vec<_>>() {
+                if get_spec_upstream(
+                    remaining.get(&task_id).unwrap(),
+                ).into_iter().any(|(upstream_id, _)| remaining.contains_key(upstream_id)) {
+                    continue;
+                }
+                did_work = true;
+                let spec = remaining.remove(&task_id).unwrap();
+                let error_count = errors.len();
+                missing_upstream.extend(get_missing_upstream(state_dynamic, &spec));
+                validate_new_task(state, state_dynamic, &mut errors, &task_id, &spec);
+                if errors.len() > error_count {
+                    continue;
+                }
+                build_task(state_dynamic, task_id.clone(), spec);
+                built.push(task_id);
+            }
+            if !did_work {
+                // Other cycles were rejected above, this is only possible via `conflicts`
+                errors.push(
+                    loga::err(
+                        format!(
+                            "Tasks [{}] couldn't be ordered for creation, they conflict with each other in both directions or with their own dependencies",
+                            remaining.keys().cloned().collect::<Vec<_>>().join(", ")
+                        ),
+                    ),
+                );
+                break;
+            }
+        }
+    }
+
+    // # Reconnect unchanged tasks that depend on replaced tasks
+    for removed_task in &removed {
+        if removed_task.downstream.is_empty() || !built.contains(&removed_task.id) {
+            continue;
+        }
+        if let Some(Task::Short(s)) = add.get(&removed_task.id) {
+            match &s.started_action {
+                None | Some(ShortTaskStartedAction::None) => { },
+                Some(ShortTaskStartedAction::TurnOff) | Some(ShortTaskStartedAction::Delete) => {
+                    for downstream_id in removed_task.downstream.keys() {
+                        errors.push(
+                            loga::err(
+                                format!(
+                                    "Task [{}] has a started action other than none but task [{}] depends on it",
+                                    removed_task.id,
+                                    downstream_id
+                                ),
+                            ),
+                        );
+                    }
+                },
+            }
+        }
+        get_task(state_dynamic, &removed_task.id).downstream.borrow_mut().extend(removed_task.downstream.clone());
+    }
+
+    // # Update templates and create instances to turn on
+    let mut old_templates = vec![];
+    let mut instances = vec![];
+    if errors.is_empty() {
+        for (task_id, spec) in &add_templates {
+            old_templates.push((task_id.clone(), state_dynamic.templates.insert(task_id.clone(), spec.clone())));
+        }
+        for task_id in &delete_templates {
+            // Existing instances are unaffected
+            old_templates.push((task_id.clone(), state_dynamic.templates.remove(task_id)));
+        }
+        for (task_id, spec) in &add_templates {
+            missing_upstream.extend(validate_new_template(state, state_dynamic, &mut errors, task_id, spec));
+        }
+        for op in &on_off {
+            if !errors.is_empty() {
+                break;
+            }
+            if state_dynamic.tasks.contains_key(&op.task) {
+                continue;
+            }
+            match instantiate_task(state, state_dynamic, &op.task) {
+                Ok(built_instances) => {
+                    instances.extend(built_instances);
+                },
+                Err(e) => {
+                    errors.push(e);
+                },
+            }
+        }
+    }
+
+    // # Put everything back if there were errors
+    if !errors.is_empty() {
+        for task_id in instances.iter().rev() {
+            state_dynamic.instances.remove(task_id);
+            delete_task(state_dynamic, task_id);
+        }
+        for task_id in built.iter().rev() {
+            delete_task(state_dynamic, task_id);
+        }
+        for removed_task in removed.into_iter().rev() {
+            restore_task(state_dynamic, removed_task);
+        }
+        for (task_id, old_template) in old_templates.into_iter().rev() {
+            match old_template {
+                Some(spec) => {
+                    state_dynamic.templates.insert(task_id, spec);
+                },
+                None => {
+                    state_dynamic.templates.remove(&task_id);
+                },
+            }
+        }
+        return Err(invalid_batch(missing_upstream, errors));
+    }
+    for task_id in &delete {
+        state_dynamic.instances.remove(task_id);
+    }
+
+    // # Turn tasks on and off
+    let mut plan = ExecutePlan::default();
+    for task_id in &built {
+        if seen_on_off.contains(task_id) {
+            continue;
+        }
+        let (default_on, user_on) = match add.get(task_id).unwrap() {
+            Task::Empty(s) => (s.default_on, s.default_on || is_in_schedule_window(&s.schedule)),
+            Task::Long(s) => (s.default_on, s.default_on || is_in_schedule_window(&s.schedule)),
+            Task::Short(s) => (s.default_on, s.default_on || is_in_schedule_window(&s.schedule)),
+        };
+        if user_on {
+            plan_task_user_on(state_dynamic, &mut plan, task_id, user_reason(match default_on {
+                true => ControlSource::DefaultOn,
+                false => ControlSource::Schedule,
+            }));
+        }
+    }
+    for op in &on_off {
+        if op.on {
+            plan_task_user_on(state_dynamic, &mut plan, &op.task, user_reason(ControlSource::User));
+        } else {
+            plan_task_user_off(state_dynamic, &mut plan, &op.task, user_reason(ControlSource::User));
+        }
+    }
+    execute_user_plan(state, state_dynamic, plan);
+    delete_unused_instances(state_dynamic);
+    return Ok(());
+}
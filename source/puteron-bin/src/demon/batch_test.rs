@@ -0,0 +1,148 @@
+#![cfg(test)]
+
+use {
+    super::{
+        batch::apply_batch,
+        state::{
+            State,
+            TaskStateSpecific,
+        },
+        task_plan_test::{
+            build_state,
+            build_test_state,
+            spec,
+            task_empty,
+        },
+        task_util::{
+            control_reason,
+            get_task,
+        },
+    },
+    puteron::interface::{
+        ipc::{
+            BatchOperation,
+            IpcError,
+            RequestTaskAdd,
+            RequestTaskDelete,
+        },
+        task::{
+            DependencyType,
+            Task,
+        },
+    },
+    std::sync::Arc,
+};
+
+fn add(task: &str, spec: Task) -> BatchOperation {
+    return BatchOperation::Add(RequestTaskAdd {
+        task: task.to_string(),
+        spec: spec,
+        unique: false,
+        restart: false,
+    });
+}
+
+fn delete(task: &str) -> BatchOperation {
+    return BatchOperation::Delete(RequestTaskDelete(task.to_string()));
+}
+
+fn apply(state: &Arc<State>, operations: Vec<BatchOperation>) -> Result<(), IpcError> {
+    let mut state_dynamic = state.dynamic.lock().unwrap();
+    return apply_batch(state, &mut state_dynamic, operations, control_reason);
+}
+
+#[test]
+fn batch_replace_with_dependent() {
+    let state = build_test_state(build_state([
+        //. .
+        task_empty("a", false, []),
+        task_empty("b", false, [("a", DependencyType::Strong)]),
+    ]));
+    let mut new_a = spec([]);
+    let Task::Empty(s) = &mut new_a else {
+        unreachable!();
+    };
+    s.labels = vec!["new".to_string()];
+    apply(&state, vec![add("a", new_a)]).map_err(|e| format!("{:?}", e)).unwrap();
+    let state_dynamic = state.dynamic.lock().unwrap();
+    let a = get_task(&state_dynamic, &"a".to_string());
+    let TaskStateSpecific::Empty(s) = &a.specific else {
+        unreachable!();
+    };
+    assert_eq!(s.spec.labels, vec!["new".to_string()]);
+    assert_eq!(a.downstream.borrow().get("b"), Some(&DependencyType::Strong));
+}
+
+#[test]
+fn batch_delete_chain() {
+    let state = build_test_state(build_state([
+        //. .
+        task_empty("a", false, []),
+        task_empty("b", false, [("a", DependencyType::Strong)]),
+        task_empty("c", false, [("b", DependencyType::Weak)]),
+        task_empty("d", false, []),
+    ]));
+    apply(&state, vec![delete("a"), delete("b"), delete("c")]).map_err(|e| format!("{:?}", e)).unwrap();
+    let state_dynamic = state.dynamic.lock().unwrap();
+    assert_eq!(state_dynamic.tasks.keys().cloned().collect::<Vec<_>>(), vec!["d".to_string()]);
+}
+
+#[test]
+fn batch_delete_with_remaining_dependent_fails() {
+    let state = build_test_state(build_state([
+        //. .
+        task_empty("a", false, []),
+        task_empty("b", false, [("a", DependencyType::Strong)]),
+    ]));
+    assert!(matches!(apply(&state, vec![delete("a")]), Err(IpcError::InvalidBatch { .. })));
+    let state_dynamic = state.dynamic.lock().unwrap();
+    assert_eq!(state_dynamic.tasks.len(), 2);
+}
+
+#[test]
+fn batch_failed_add_rolls_back() {
+    let mut state_dynamic = build_state([
+        //. .
+        task_empty("a", false, []),
+        task_empty("x", false, []),
+    ]);
+    state_dynamic.templates.insert("t@".to_string(), spec([]));
+    let state = build_test_state(state_dynamic);
+    let res = apply(&state, vec![
+        //. .
+        delete("x"),
+        add("n", spec([])),
+        add("a", spec([("n", DependencyType::Weak)])),
+        add("t@", spec([("a", DependencyType::Strong)])),
+        add("u@", spec([("missing", DependencyType::Strong)])),
+    ]);
+    let Err(IpcError::InvalidBatch { missing_upstream, .. }) = res else {
+        panic!("Expected invalid batch");
+    };
+    assert_eq!(missing_upstream, vec!["missing".to_string()]);
+    let state_dynamic = state.dynamic.lock().unwrap();
+    assert!(state_dynamic.tasks.contains_key("x"));
+    assert!(!state_dynamic.tasks.contains_key("n"));
+    let TaskStateSpecific::Empty(a) = &get_task(&state_dynamic, &"a".to_string()).specific else {
+        unreachable!();
+    };
+    assert!(a.spec.upstream.is_empty());
+    assert!(!state_dynamic.templates.contains_key("u@"));
+    let Some(Task::Empty(t)) = state_dynamic.templates.get("t@") else {
+        panic!("Expected original template");
+    };
+    assert!(t.upstream.is_empty());
+}
+
+#[test]
+fn batch_conflicting_dependencies_rejected() {
+    let state = build_test_state(build_state([]));
+    let res = apply(&state, vec![
+        //. .
+        add("a", spec([("b", DependencyType::Strong)])),
+        add("b", spec([("a", DependencyType::Conflicts)])),
+    ]);
+    assert!(matches!(res, Err(IpcError::InvalidBatch { .. })));
+    let state_dynamic = state.dynamic.lock().unwrap();
+    assert!(state_dynamic.tasks.is_empty());
+}
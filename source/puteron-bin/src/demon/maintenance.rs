@@ -0,0 +1,125 @@
+use {
+    super::{
+        state::{
+            State,
+            StateDynamic,
+        },
+        task_execute::{
+            set_task_user_off,
+            set_task_user_on,
+        },
+        task_util::{
+            control_reason,
+            get_task_labels,
+            maybe_get_task,
+        },
+    },
+    chrono::{
+        DateTime,
+        Utc,
+    },
+    loga::ea,
+    puteron::interface::{
+        base::TaskId,
+        demon::MaintenanceWindow,
+        ipc::{
+            ControlReason,
+            ControlSource,
+        },
+    },
+    std::{
+        collections::BTreeSet,
+        sync::Arc,
+    },
+};
+
+/// Returns window boundaries that haven't passed yet, latest first (for popping).
+/// The bool is true for the start of a window.
+pub(crate) fn build_maintenance_events(state: &State, now: DateTime<Utc>) -> Vec<(DateTime<Utc>, String, bool)> {
+    let mut out = vec![];
+    for (name, window) in &state.maintenance_windows {
+        if window.end <= now {
+            continue;
+        }
+        out.push((window.start, name.clone(), true));
+        out.push((window.end, name.clone(), false));
+    }
+
+    // Ends before starts at the same time, so back to back windows don't restore
+    // tasks in between
+    out.sort_by(|a, b| (a.0, a.2).cmp(&(b.0, b.2)));
+    out.reverse();
+    return out;
+}
+
+/// Tasks listed by id (whether they exist or not) plus existing tasks with any of
+/// the window's labels.
+pub(crate) fn get_window_turn_off_tasks(
+    state_dynamic: &StateDynamic,
+    window: &MaintenanceWindow,
+) -> BTreeSet<TaskId> {
+    let mut out = window.turn_off.iter().cloned().collect::<BTreeSet<_>>();
+    if !window.turn_off_labels.is_empty() {
+        for (task_id, task) in &state_dynamic.tasks {
+            let task = &state_dynamic.task_alloc[*task];
+            if get_task_labels(task).iter().any(|l| window.turn_off_labels.contains(l)) {
+                out.insert(task_id.clone());
+            }
+        }
+    }
+    return out;
+}
+
+pub(crate) fn maintenance_start(state: &Arc<State>, state_dynamic: &mut StateDynamic, name: &String) {
+    let window = state.maintenance_windows.get(name).unwrap();
+    state.log.log_with(loga::INFO, "Maintenance window starting", ea!(window = name));
+    let mut restore = vec![];
+    for task_id in &get_window_turn_off_tasks(state_dynamic, window) {
+        let Some(task) = maybe_get_task(state_dynamic, task_id) else {
+            state
+                .log
+                .log_with(loga::WARN, "Maintenance window refers to unknown task", ea!(window = name, task = task_id));
+            continue;
+        };
+        if task.direct_on.get().0 {
+            restore.push(task_id.clone());
+        }
+        set_task_user_off(state, state_dynamic, task_id, ControlReason {
+            maintenance_window: Some(name.clone()),
+            ..control_reason(ControlSource::Maintenance)
+        });
+    }
+    state_dynamic.maintenance_restore.insert(name.clone(), restore);
+}
+
+pub(crate) fn maintenance_end(state: &Arc<State>, state_dynamic: &mut StateDynamic, name: &String) {
+    state.log.log_with(loga::INFO, "Maintenance window ending", ea!(window = name));
+    let Some(restore) = state_dynamic.maintenance_restore.remove(name) else {
+        return;
+    };
+    for task_id in restore {
+        let Some(task) = maybe_get_task(state_dynamic, &task_id) else {
+            continue;
+        };
+
+        // Leave it alone if something else changed it during the window (ex: a user
+        // turned it on and off again)
+        let still_off_for_window = !task.direct_on.get().0 && task.direct_on_reason.borrow().as_ref().map(|r| {
+            return matches!(r.source, ControlSource::Maintenance) && r.maintenance_window.as_ref() == Some(name);
+        }).unwrap_or(false);
+        if !still_off_for_window {
+            state
+                .log
+                .log_with(
+                    loga::DEBUG,
+                    "Task was changed during the maintenance window, not turning it back on",
+                    ea!(window = name, task = task_id),
+                );
+            continue;
+        }
+        set_task_user_on(state, state_dynamic, &task_id, ControlReason {
+            maintenance_window: Some(name.clone()),
+            ..control_reason(ControlSource::Maintenance)
+        });
+    }
+}
@@ -1,13 +1,41 @@
+mod access;
+mod batch;
 mod state;
 mod schedule;
+mod maintenance;
 mod task_create_delete;
 mod task_util;
 mod task_execute;
 mod task_plan;
 mod task_plan_test;
+mod batch_test;
+mod schedule_test;
+mod task_create_delete_test;
+mod task_execute_test;
+mod wait;
 
 use {
-    crate::spec::merge_specs,
+    access::{
+        access_class_name,
+        get_access_class,
+        get_controlled_tasks,
+        get_unknown_access_names,
+        is_access_allowed,
+        is_task_control_allowed,
+        load_id_names,
+    },
+    batch::apply_batch,
+    crate::{
+        ipc_util::{
+            encode_ipc_error,
+            peer_credentials,
+            PeerCredentials,
+        },
+        spec::{
+            instantiate_template,
+            merge_specs,
+        },
+    },
     aargvark::{
         traits_impls::AargvarkJson,
         Aargvark,
@@ -23,6 +51,11 @@ use {
     },
     puteron::interface::{
         self,
+        base::{
+            is_template_id,
+            split_instance_id,
+            TaskId,
+        },
         demon::Config,
         ipc::{
             ipc::{
@@ -30,18 +63,37 @@ use {
                 ServerResp,
             },
             ipc_path,
+            ControlReason,
+            ControlSource,
+            GraphEdge,
+            GraphNode,
+            GraphNodeType,
+            IpcError,
             RespScheduleEntry,
+            RespTaskGraph,
+            RespTaskWhy,
+            RespTaskPlan,
             TaskDependencyStatus,
             TaskStatus,
+            WhyBlocker,
         },
         task::{
             DependencyType,
+            ShortTaskStartedAction,
             Task,
         },
     },
+    maintenance::{
+        build_maintenance_events,
+        maintenance_end,
+        maintenance_start,
+    },
     schedule::{
+        is_in_schedule_window,
         pop_schedule,
-        populate_schedule,
+        schedule_skip_reason,
+        schedule_task,
+        unschedule_task,
     },
     state::{
         State,
@@ -49,8 +101,17 @@ use {
         TaskStateSpecific,
     },
     std::{
-        collections::HashMap,
+        collections::{
+            BTreeMap,
+            HashMap,
+            HashSet,
+            VecDeque,
+        },
         env,
+        path::{
+            Path,
+            PathBuf,
+        },
         sync::{
             Arc,
             Mutex,
@@ -59,17 +120,35 @@ use {
     task_create_delete::{
         build_task,
         delete_task,
+        delete_unused_instances,
+        instantiate_task,
         validate_new_task,
+        validate_new_template,
+        validate_task_graph,
     },
     task_execute::{
+        reload_task,
+        replace_task_restart,
+        restart_task,
         set_task_user_off,
         set_task_user_on,
     },
+    task_plan::plan_dry_run,
     task_util::{
+        control_reason,
+        get_conflicting_tasks,
+        get_missing_upstream,
+        get_spec_upstream,
         get_task,
+        get_task_spec,
+        get_task_state,
+        get_task_upstream,
+        BUILTIN_VARIABLES,
+        is_same_task_spec,
         is_task_on,
         is_task_started,
         is_task_stopped,
+        is_upstream_satisfied,
         maybe_get_task,
         walk_task_upstream,
     },
@@ -86,6 +165,7 @@ use {
             Instant,
         },
     },
+    wait::wait_tasks,
 };
 
 #[derive(Aargvark)]
@@ -96,6 +176,21 @@ pub struct DemonRunArgs {
     validate: Option<()>,
 }
 
+fn xdg_dir(var: &str, home_subdir: &str, config_field: &str) -> Result<PathBuf, loga::Error> {
+    if let Ok(d) = env::var(var) {
+        return Ok(PathBuf::from(d));
+    }
+    let Ok(home) = env::var("HOME") else {
+        return Err(
+            loga::err_with(
+                "Running as a user but neither the XDG directory environment variable nor HOME are set, specify the directory in the config",
+                ea!(variable = var, config_field = config_field),
+            ),
+        );
+    };
+    return Ok(PathBuf::from(home).join(home_subdir));
+}
+
 pub async fn main(log: &Log, args: DemonRunArgs) -> Result<(), loga::Error> {
     let config = args.config.value;
     let mut specs = merge_specs(log, &config.task_dirs, None).await?;
@@ -109,49 +204,130 @@ pub async fn main(log: &Log, args: DemonRunArgs) -> Result<(), loga::Error> {
     }
     env.extend(config.environment.add);
 
+    // # Prep base directories
+    //
+    // Like `ipc_path`, assume running as a user if `XDG_RUNTIME_DIR` is set.
+    let user_mode = env::var("XDG_RUNTIME_DIR").is_ok();
+    let runtime_dir = match config.runtime_dir {
+        Some(d) => d,
+        None => match env::var("XDG_RUNTIME_DIR") {
+            Ok(d) => PathBuf::from(d),
+            Err(_) => PathBuf::from("/run"),
+        },
+    };
+    let state_dir = match config.state_dir {
+        Some(d) => d,
+        None => if user_mode {
+            xdg_dir("XDG_STATE_HOME", ".local/state", "state_dir")?
+        } else {
+            PathBuf::from("/var/lib")
+        },
+    };
+    let cache_dir = match config.cache_dir {
+        Some(d) => d,
+        None => if user_mode {
+            xdg_dir("XDG_CACHE_HOME", ".cache", "cache_dir")?
+        } else {
+            PathBuf::from("/var/cache")
+        },
+    };
+    let logs_dir = match config.logs_dir {
+        Some(d) => d,
+        None => if user_mode {
+            xdg_dir("XDG_STATE_HOME", ".local/state", "logs_dir")?.join("log")
+        } else {
+            PathBuf::from("/var/log")
+        },
+    };
+
+    // # Load user and group names for access lists
+    let user_ids = load_id_names(Path::new("/etc/passwd")).await?;
+    let group_ids = load_id_names(Path::new("/etc/group")).await?;
+
     // # Create state
     let notify_reschedule = Arc::new(Notify::new());
     let state = Arc::new(State {
         log: log.clone(),
         task_dirs: config.task_dirs,
         env: env,
+        env_files: config.environment.files,
+        maintenance_windows: config.maintenance_windows,
+        variables: config.variables,
+        runtime_dir: runtime_dir,
+        state_dir: state_dir,
+        cache_dir: cache_dir,
+        logs_dir: logs_dir,
+        access: config.access,
+        user_ids: user_ids,
+        group_ids: group_ids,
         dynamic: Mutex::new(StateDynamic {
             task_alloc: Default::default(),
             tasks: Default::default(),
             schedule_top: Default::default(),
             schedule: Default::default(),
             notify_reschedule: notify_reschedule.clone(),
+            maintenance_restore: Default::default(),
+            templates: Default::default(),
+            instances: Default::default(),
+            audit_log: Default::default(),
+            replacements: Default::default(),
         }),
+        notify_state: Notify::new(),
         tokio_tasks: Default::default(),
     });
     {
         let mut state_dynamic = state.dynamic.lock().unwrap();
 
+        // # Set aside templates, only instances of them are run
+        for task_id in specs.keys().filter(|x| is_template_id(x)).cloned().collect::<Vec<_>>() {
+            let spec = specs.remove(&task_id).unwrap();
+            state_dynamic.templates.insert(task_id, spec);
+        }
+
         // # Create task states from specs
         let mut errors = vec![];
+        validate_task_graph(&state_dynamic, &specs, &mut errors);
+        if !errors.is_empty() {
+            return Err(loga::agg_err("One or more errors with task specifications", errors));
+        }
+        if let Some(access) = &state.access {
+            for (class, list) in [("read", &access.read), ("control", &access.control), ("manage", &access.manage)] {
+                for unknown in get_unknown_access_names(&state, list) {
+                    errors.push(loga::err(format!("Access list [{}] refers to unknown {}", class, unknown)));
+                }
+            }
+        }
+        for name in state.variables.keys() {
+            if BUILTIN_VARIABLES.contains(&name.as_str()) || name.starts_with("env:") {
+                errors.push(loga::err_with("Config variable name is reserved", ea!(variable = name)));
+            }
+        }
         while !specs.is_empty() {
             let mut did_work = false;
             let task_ids = specs.keys().cloned().collect::<Vec<_>>();
             for task_id in &task_ids {
                 // Find frontier tasks (all upstreams created)
-                let upstream: Vec<&String> = match &specs.get(task_id).unwrap() {
-                    Task::Empty(s) => {
-                        s.upstream.keys().collect()
-                    },
-                    Task::Long(s) => {
-                        s.upstream.keys().collect()
-                    },
-                    Task::Short(s) => {
-                        s.upstream.keys().collect()
-                    },
-                };
+                let upstream: Vec<String> =
+                    get_spec_upstream(specs.get(task_id).unwrap()).into_iter().map(|(id, _)| id.clone()).collect();
                 let mut all_upstream_created = true;
                 for upstream_id in upstream {
-                    if state_dynamic.tasks.contains_key(upstream_id) {
+                    if state_dynamic.tasks.contains_key(&upstream_id) {
                         // created, ok
-                    } else if specs.contains_key(upstream_id) {
+                    } else if specs.contains_key(&upstream_id) {
                         // not yet created
                         all_upstream_created = false;
+                    } else if let Some(spec) =
+                        split_instance_id(
+                            &upstream_id,
+                        ).and_then(
+                            |(template_id, instance)| state_dynamic
+                                .templates
+                                .get(&template_id)
+                                .map(|template| instantiate_template(template, instance)),
+                        ) {
+                        // instance of a template without its own spec, create from the template
+                        specs.insert(upstream_id, spec);
+                        all_upstream_created = false;
                     } else {
                         // missing, pretend ok - missing will be logged later when validating
                     }
@@ -163,19 +339,37 @@ pub async fn main(log: &Log, args: DemonRunArgs) -> Result<(), loga::Error> {
                 // All deps created, now create this task
                 did_work = true;
                 let spec = specs.remove(task_id).unwrap();
-                validate_new_task(&state_dynamic, &mut errors, task_id, &spec);
+                validate_new_task(&state, &state_dynamic, &mut errors, task_id, &spec);
                 build_task(&mut state_dynamic, task_id.clone(), spec);
             }
             if !did_work {
+                // Other cycles were rejected above, this is only possible via `conflicts`
                 errors.push(
                     loga::err_with(
-                        "One or more tasks have cycles in their dependencies",
+                        "Tasks couldn't be ordered for creation, they conflict with each other in both directions or with their own dependencies",
                         ea!(tasks = task_ids.dbg_str()),
                     ),
                 );
                 break;
             }
         }
+        for (template_id, spec) in &state_dynamic.templates {
+            validate_new_template(&state, &state_dynamic, &mut errors, template_id, spec);
+        }
+        for (name, window) in &state.maintenance_windows {
+            if window.end <= window.start {
+                errors.push(loga::err_with("Maintenance window ends before it starts", ea!(window = name)));
+            }
+            for task_id in window.turn_off.iter().chain(window.suppress_schedule.iter().flatten()) {
+                if !state_dynamic.tasks.contains_key(task_id) {
+                    log.log_with(
+                        loga::WARN,
+                        "Maintenance window refers to unknown task",
+                        ea!(window = name, task = task_id),
+                    );
+                }
+            }
+        }
         if !errors.is_empty() {
             return Err(loga::agg_err("One or more errors with task specifications", errors));
         }
@@ -192,31 +386,36 @@ pub async fn main(log: &Log, args: DemonRunArgs) -> Result<(), loga::Error> {
         // ## Start default-on tasks
         for (id, task) in state_dynamic.tasks.iter().map(|(x, y)| (x.clone(), y.clone())).collect::<Vec<_>>() {
             let task = &state_dynamic.task_alloc[task];
+            let default_on;
             let user_on;
             match &task.specific {
                 TaskStateSpecific::Empty(s) => {
-                    user_on = s.spec.default_on;
+                    default_on = s.spec.default_on;
+                    user_on = s.spec.default_on || is_in_schedule_window(&s.spec.schedule);
                 },
                 TaskStateSpecific::Long(s) => {
-                    user_on = s.spec.default_on;
+                    default_on = s.spec.default_on;
+                    user_on = s.spec.default_on || is_in_schedule_window(&s.spec.schedule);
                 },
                 TaskStateSpecific::Short(s) => {
-                    user_on = s.spec.default_on;
+                    default_on = s.spec.default_on;
+                    user_on = s.spec.default_on || is_in_schedule_window(&s.spec.schedule);
                 },
             }
             log.log_with(loga::DEBUG, "Reporting task initial state-", ea!(task = task.id, on = user_on));
             if !user_on {
                 continue;
             }
-            set_task_user_on(&state, &mut state_dynamic, &id);
+            set_task_user_on(&state, &mut state_dynamic, &id, control_reason(match default_on {
+                true => ControlSource::DefaultOn,
+                false => ControlSource::Schedule,
+            }));
         }
 
-        // ## Schedule tasks
-        populate_schedule(&mut state_dynamic);
-
-        // Get initially scheduled task
+        // Get initially scheduled task (tasks are scheduled when built)
         schedule_next = pop_schedule(&mut state_dynamic);
     }
+    let mut maintenance_events = build_maintenance_events(&state, Utc::now());
 
     // ## Handle ipc + other inputs (signals)
     let mut sigint = tokio::signal::unix::signal(SignalKind::interrupt()).context("Error hooking into SIGINT")?;
@@ -226,7 +425,7 @@ pub async fn main(log: &Log, args: DemonRunArgs) -> Result<(), loga::Error> {
     fn task_off_all(state: &Arc<State>) {
         let mut state_dynamic = state.dynamic.lock().unwrap();
         for task_id in state_dynamic.tasks.keys().cloned().collect::<Vec<_>>() {
-            set_task_user_off(state, &mut state_dynamic, &task_id);
+            set_task_user_off(state, &mut state_dynamic, &task_id, control_reason(ControlSource::Shutdown));
         }
     }
 
@@ -259,12 +458,22 @@ pub async fn main(log: &Log, args: DemonRunArgs) -> Result<(), loga::Error> {
                         continue;
                     },
                 };
-                spawn(handle_ipc(state.clone(), stream));
+                let peer = match peer_credentials(&stream) {
+                    Ok(p) => Some(p),
+                    Err(e) => {
+                        log.log_err(loga::WARN, e);
+                        None
+                    },
+                };
+                spawn(handle_ipc(state.clone(), stream, peer));
             },
             _ = notify_reschedule.notified() => {
                 let mut state_dynamic = state.dynamic.lock().unwrap();
                 if let Some((delay, spec)) = schedule_next {
-                    state_dynamic.schedule.entry(delay).or_default().push(spec);
+                    // Skip if invalidated (task deleted or rescheduled)
+                    if state_dynamic.schedule_top.is_some() {
+                        state_dynamic.schedule.entry(delay).or_default().push(spec);
+                    }
                 }
                 schedule_next = pop_schedule(&mut state_dynamic);
             },
@@ -276,18 +485,79 @@ pub async fn main(log: &Log, args: DemonRunArgs) -> Result<(), loga::Error> {
             if schedule_next.is_some() => {
                 let (_, spec) = schedule_next.unwrap();
                 let mut state_dynamic = state.dynamic.lock().unwrap();
-                log.log_with(
-                    loga::DEBUG,
-                    "Timer triggered for scheduled task, turning on.",
-                    ea!(task = spec.0, schedule = spec.1.dbg_str()),
-                );
-                set_task_user_on(&state, &mut state_dynamic, &spec.0);
-                state_dynamic
-                    .schedule
-                    .entry(schedule::calc_next_instant(Utc::now(), Instant::now(), &spec.1, false))
-                    .or_default()
-                    .push(spec);
+                if state_dynamic.schedule_top.is_some() && !spec.2 {
+                    // Leave the task alone if something else (ex: a user) turned it on or off
+                    // since the window started
+                    let schedule_on = maybe_get_task(&state_dynamic, &spec.0).map(|task| {
+                        return task.direct_on.get().0 &&
+                            task
+                                .direct_on_reason
+                                .borrow()
+                                .as_ref()
+                                .map(|r| matches!(r.source, ControlSource::Schedule))
+                                .unwrap_or(false);
+                    }).unwrap_or(false);
+                    if schedule_on {
+                        log.log_with(
+                            loga::DEBUG,
+                            "Timer triggered for scheduled task window end, turning off.",
+                            ea!(task = spec.0, schedule = spec.1.dbg_str()),
+                        );
+                        set_task_user_off(&state, &mut state_dynamic, &spec.0, ControlReason {
+                            rule: Some(spec.1.clone()),
+                            ..control_reason(ControlSource::Schedule)
+                        });
+                    } else {
+                        log.log_with(
+                            loga::DEBUG,
+                            "Timer triggered for scheduled task window end, but it wasn't turned on by the schedule.",
+                            ea!(task = spec.0, schedule = spec.1.dbg_str()),
+                        );
+                    }
+                    state_dynamic
+                        .schedule
+                        .entry(schedule::calc_next_instant(Utc::now(), Instant::now(), &spec.1, false))
+                        .or_default()
+                        .push(spec);
+                } else if state_dynamic.schedule_top.is_some() {
+                    if let Some(reason) = schedule_skip_reason(&state, &state_dynamic, &spec.0, Utc::now()) {
+                        log.log_with(
+                            loga::DEBUG,
+                            "Timer triggered for scheduled task, but skipping.",
+                            ea!(task = spec.0, schedule = spec.1.dbg_str(), reason = reason),
+                        );
+                    } else {
+                        log.log_with(
+                            loga::DEBUG,
+                            "Timer triggered for scheduled task, turning on.",
+                            ea!(task = spec.0, schedule = spec.1.dbg_str()),
+                        );
+                        set_task_user_on(&state, &mut state_dynamic, &spec.0, ControlReason {
+                            rule: Some(spec.1.clone()),
+                            ..control_reason(ControlSource::Schedule)
+                        });
+                    }
+                    state_dynamic
+                        .schedule
+                        .entry(schedule::calc_next_instant(Utc::now(), Instant::now(), &spec.1, false))
+                        .or_default()
+                        .push(spec);
+                }
                 schedule_next = schedule::pop_schedule(&mut state_dynamic);
+            },
+            _ = async {
+                if let Some((at, _, _)) = maintenance_events.last() {
+                    sleep_until(Instant::now() + (*at - Utc::now()).to_std().unwrap_or_default()).await;
+                }
+            },
+            if !maintenance_events.is_empty() => {
+                let (_, name, start) = maintenance_events.pop().unwrap();
+                let mut state_dynamic = state.dynamic.lock().unwrap();
+                if start {
+                    maintenance_start(&state, &mut state_dynamic, &name);
+                } else {
+                    maintenance_end(&state, &mut state_dynamic, &name);
+                }
             }
         }
     }
@@ -298,8 +568,14 @@ pub async fn main(log: &Log, args: DemonRunArgs) -> Result<(), loga::Error> {
     return Ok(());
 }
 
-async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
+async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn, peer: Option<PeerCredentials>) {
     let log = state.log.fork(ea!(sys = "ipc"));
+    let peer_ids = peer.as_ref().map(|p| (p.uid, p.pid));
+    let user_reason = move |source| ControlReason {
+        uid: peer_ids.map(|p| p.0),
+        pid: peer_ids.map(|p| p.1),
+        ..control_reason(source)
+    };
     loop {
         let req = match conn.recv_req().await {
             Ok(Some(message)) => message,
@@ -311,11 +587,37 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                 return;
             },
         };
+        let access_class = get_access_class(&req);
+        let controlled_tasks = get_controlled_tasks(&req);
+        if !is_access_allowed(&state, peer.as_ref(), access_class) &&
+            (controlled_tasks.is_empty() ||
+                !controlled_tasks.iter().all(|t| is_task_control_allowed(&state, peer.as_ref(), t))) {
+            log.log_with(
+                loga::DEBUG,
+                "Denying ipc request",
+                ea!(
+                    uid = peer_ids.map(|p| p.0).dbg_str(),
+                    class = access_class_name(access_class),
+                    tasks = controlled_tasks.dbg_str()
+                ),
+            );
+            let resp = ServerResp::err(encode_ipc_error(&IpcError::PermissionDenied {
+                access: access_class_name(access_class).to_string(),
+                task: match controlled_tasks.as_slice() {
+                    [task] => Some((*task).clone()),
+                    _ => None,
+                },
+            }));
+            if let Err(e) = conn.send_resp(resp).await {
+                log.log_err(loga::DEBUG, loga::err(e).context("Error writing response"));
+            }
+            continue;
+        }
         let resp = {
             let state = state.clone();
             let log = log.clone();
             async move {
-                ta_return!(ipc::ServerResp, String);
+                ta_return!(ipc::ServerResp, IpcError);
                 match req {
                     ipc::ServerReq::TaskList(rr, _) => {
                         let state_dynamic = state.dynamic.lock().unwrap();
@@ -324,75 +626,156 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                     ipc::ServerReq::TaskAdd(rr, m) => {
                         let mut state_dynamic = state.dynamic.lock().unwrap();
 
+                        // # Templates are only stored, instances are created when turned on
+                        if is_template_id(&m.task) {
+                            if m.unique && state_dynamic.templates.contains_key(&m.task) {
+                                return Err(IpcError::TaskExists { task: m.task });
+                            }
+                            let old = state_dynamic.templates.insert(m.task.clone(), m.spec.clone());
+                            let mut errors = vec![];
+                            let missing_upstream =
+                                validate_new_template(&state, &state_dynamic, &mut errors, &m.task, &m.spec);
+                            if !errors.is_empty() {
+                                match old {
+                                    Some(old) => {
+                                        state_dynamic.templates.insert(m.task.clone(), old);
+                                    },
+                                    None => {
+                                        state_dynamic.templates.remove(&m.task);
+                                    },
+                                }
+                                return Err(IpcError::InvalidSpec {
+                                    task: m.task,
+                                    missing_upstream: missing_upstream,
+                                    errors: errors.into_iter().map(|x| x.to_string()).collect(),
+                                });
+                            }
+                            return Ok(rr(()));
+                        }
+
                         // # Check + delete the old task if it exists
+                        let mut old = None;
+                        let mut downstream = HashMap::new();
                         if let Some(task) = maybe_get_task(&state_dynamic, &m.task) {
-                            if !m.unique {
-                                return Err(format!("A task with this ID already exists"));
+                            if m.unique {
+                                return Err(IpcError::TaskExists { task: m.task });
                             }
-                            if !is_task_stopped(task) {
-                                return Err(format!("Task isn't stopped yet"));
+                            if !m.restart && !is_task_stopped(task) {
+                                return Err(IpcError::TaskNotStopped { task: m.task });
                             }
-                            let same = match (&m.spec, &task.specific) {
-                                (Task::Empty(new), TaskStateSpecific::Empty(old)) => new == &old.spec,
-                                (Task::Long(new), TaskStateSpecific::Long(old)) => new == &old.spec,
-                                (Task::Short(new), TaskStateSpecific::Short(old)) => new == &old.spec,
-                                _ => false,
-                            };
-                            if same {
+                            if is_same_task_spec(task, &m.spec) {
                                 return Ok(rr(()));
                             }
-                            delete_task(&mut state_dynamic, &m.task);
+
+                            // Downstream tasks still refer to this task, keep their edges
+                            downstream = task.downstream.borrow().clone();
+
+                            // When restarting, the old task is replaced once it stops
+                            if !m.restart {
+                                old = Some(get_task_spec(task));
+                                delete_task(&mut state_dynamic, &m.task);
+                            }
                         }
 
                         // # Check new task spec
                         //
-                        // Check for broken upstreams
+                        // Check for broken upstreams and cycles
                         let mut errors = vec![];
-                        validate_new_task(&state_dynamic, &mut errors, &m.task, &m.spec);
+                        let missing_upstream = get_missing_upstream(&state_dynamic, &m.spec);
+                        validate_new_task(&state, &state_dynamic, &mut errors, &m.task, &m.spec);
+                        validate_task_graph(
+                            &state_dynamic,
+                            &BTreeMap::from([(m.task.clone(), m.spec.clone())]),
+                            &mut errors,
+                        );
+                        if let Task::Short(s) = &m.spec {
+                            match &s.started_action {
+                                None | Some(ShortTaskStartedAction::None) => { },
+                                Some(ShortTaskStartedAction::TurnOff) | Some(ShortTaskStartedAction::Delete) => {
+                                    for downstream_id in downstream.keys() {
+                                        errors.push(
+                                            loga::err(
+                                                format!(
+                                                    "Task [{}] has a started action other than none but task [{}] depends on it",
+                                                    m.task,
+                                                    downstream_id
+                                                ),
+                                            ),
+                                        );
+                                    }
+                                },
+                            }
+                        }
                         if !errors.is_empty() {
-                            return Err(
-                                format!(
-                                    "Task has errors:\n{}",
-                                    errors.into_iter().map(|x| format!("- {}", x)).collect::<Vec<_>>().join("\n")
-                                ),
-                            );
+                            // Put the old task back
+                            if let Some(old_spec) = old {
+                                build_task(&mut state_dynamic, m.task.clone(), old_spec);
+                                *get_task(&state_dynamic, &m.task).downstream.borrow_mut() = downstream;
+                            }
+                            return Err(IpcError::InvalidSpec {
+                                task: m.task,
+                                missing_upstream: missing_upstream,
+                                errors: errors.into_iter().map(|x| x.to_string()).collect(),
+                            });
+                        }
+
+                        // # Replace the running task in place
+                        if m.restart && state_dynamic.tasks.contains_key(&m.task) {
+                            replace_task_restart(&state, &mut state_dynamic, &m.task, m.spec);
+                            return Ok(rr(()));
                         }
 
                         // # Create task
-                        let user_on = match &m.spec {
-                            Task::Empty(s) => s.default_on,
-                            Task::Long(s) => s.default_on,
-                            Task::Short(s) => s.default_on,
+                        let (default_on, user_on) = match &m.spec {
+                            Task::Empty(s) => (s.default_on, s.default_on || is_in_schedule_window(&s.schedule)),
+                            Task::Long(s) => (s.default_on, s.default_on || is_in_schedule_window(&s.schedule)),
+                            Task::Short(s) => (s.default_on, s.default_on || is_in_schedule_window(&s.schedule)),
                         };
                         build_task(&mut state_dynamic, m.task.clone(), m.spec);
+                        *get_task(&state_dynamic, &m.task).downstream.borrow_mut() = downstream;
 
                         // # Turn on maybe
                         if user_on {
-                            set_task_user_on(&state, &mut state_dynamic, &m.task);
+                            set_task_user_on(&state, &mut state_dynamic, &m.task, user_reason(match default_on {
+                                true => ControlSource::DefaultOn,
+                                false => ControlSource::Schedule,
+                            }));
                         }
                         return Ok(rr(()));
                     },
                     ipc::ServerReq::TaskDelete(rr, m) => {
                         let mut state_dynamic = state.dynamic.lock().unwrap();
+                        if is_template_id(&m.0) {
+                            // Existing instances are unaffected
+                            state_dynamic.templates.remove(&m.0);
+                            return Ok(rr(()));
+                        }
                         let Some(task) = maybe_get_task(&state_dynamic, &m.0) else {
                             return Ok(rr(()));
                         };
                         if !is_task_stopped(&task) {
-                            return Err(format!("Task isn't stopped yet"));
+                            return Err(IpcError::TaskNotStopped { task: m.0 });
                         }
                         delete_task(&mut state_dynamic, &m.0);
+                        state_dynamic.instances.remove(&m.0);
+                        return Ok(rr(()));
+                    },
+                    ipc::ServerReq::Batch(rr, m) => {
+                        let mut state_dynamic = state.dynamic.lock().unwrap();
+                        apply_batch(&state, &mut state_dynamic, m.operations, &user_reason)?;
                         return Ok(rr(()));
                     },
                     ipc::ServerReq::TaskGetStatus(rr, m) => {
                         let state_dynamic = state.dynamic.lock().unwrap();
                         let Some(task) = maybe_get_task(&state_dynamic, &m.0) else {
-                            return Err(format!("Unknown task [{}]", m.0));
+                            return Err(IpcError::UnknownTask { task: m.0 });
                         };
                         return Ok(rr(TaskStatus {
                             direct_on: task.direct_on.get().0,
                             direct_on_at: task.direct_on.get().1,
                             transitive_on: task.transitive_on.get().0,
                             transitive_on_at: task.transitive_on.get().1,
+                            direct_on_reason: task.direct_on_reason.borrow().clone(),
                             specific: match &task.specific {
                                 TaskStateSpecific::Empty(s) => interface::ipc::TaskStatusSpecific::Empty(
                                     interface::ipc::TaskStatusSpecificEmpty {
@@ -421,8 +804,11 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                     },
                     ipc::ServerReq::TaskGetSpec(rr, m) => {
                         let state_dynamic = state.dynamic.lock().unwrap();
+                        if let Some(template) = state_dynamic.templates.get(&m.0) {
+                            return Ok(rr(template.clone()));
+                        }
                         let Some(task) = maybe_get_task(&state_dynamic, &m.0) else {
-                            return Err(format!("Unknown task [{}]", m.0));
+                            return Err(IpcError::UnknownTask { task: m.0 });
                         };
                         let out;
                         match &task.specific {
@@ -441,32 +827,73 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                     ipc::ServerReq::TaskOnOff(rr, m) => {
                         let mut state_dynamic = state.dynamic.lock().unwrap();
                         if !state_dynamic.tasks.contains_key(&m.task) {
-                            return Err(format!("Unknown task [{}]", m.task));
+                            if !m.on || split_instance_id(&m.task).is_none() {
+                                return Err(IpcError::UnknownTask { task: m.task });
+                            }
+                            instantiate_task(&state, &mut state_dynamic, &m.task).map_err(|e| IpcError::InvalidSpec {
+                                task: m.task.clone(),
+                                missing_upstream: vec![],
+                                errors: vec![e.to_string()],
+                            })?;
                         }
                         if m.on {
-                            set_task_user_on(&state, &mut state_dynamic, &m.task);
+                            set_task_user_on(&state, &mut state_dynamic, &m.task, user_reason(ControlSource::User));
                             return Ok(rr(()));
                         } else {
-                            set_task_user_off(&state, &mut state_dynamic, &m.task);
+                            set_task_user_off(&state, &mut state_dynamic, &m.task, user_reason(ControlSource::User));
+                            delete_unused_instances(&mut state_dynamic);
                             return Ok(rr(()));
                         }
                     },
+                    ipc::ServerReq::TaskRunNow(rr, m) => {
+                        let mut state_dynamic = state.dynamic.lock().unwrap();
+                        let Some(task) = maybe_get_task(&state_dynamic, &m.task) else {
+                            return Err(IpcError::UnknownTask { task: m.task });
+                        };
+                        let TaskStateSpecific::Short(specific) = &task.specific else {
+                            return Err(IpcError::NotShortTask { task: m.task });
+                        };
+                        if is_task_on(task) || !is_task_stopped(task) {
+                            // Already running (or will run), don't silently do nothing
+                            return Err(IpcError::TaskNotStopped { task: m.task });
+                        }
+                        specific.triggered.set(true);
+                        let rules = specific.spec.schedule.clone();
+                        set_task_user_on(&state, &mut state_dynamic, &m.task, user_reason(ControlSource::RunNow));
+                        if m.reset_schedule {
+                            unschedule_task(&mut state_dynamic, &m.task);
+                            schedule_task(&mut state_dynamic, &m.task, &rules, false);
+                        }
+                        return Ok(rr(()));
+                    },
+                    ipc::ServerReq::TaskRestart(rr, m) => {
+                        let mut state_dynamic = state.dynamic.lock().unwrap();
+                        if !state_dynamic.tasks.contains_key(&m.0) {
+                            return Err(IpcError::UnknownTask { task: m.0 });
+                        }
+                        restart_task(&state, &mut state_dynamic, &m.0);
+                        return Ok(rr(()));
+                    },
+                    ipc::ServerReq::TaskReload(rr, m) => {
+                        reload_task(&state, &m.task, m.wait).await?;
+                        return Ok(rr(()));
+                    },
                     ipc::ServerReq::TaskWaitStarted(rr, m) => {
                         let (notify_tx, notify_rx) = oneshot::channel();
                         {
                             let state_dynamic = state.dynamic.lock().unwrap();
                             let Some(task) = maybe_get_task(&state_dynamic, &m.0) else {
-                                return Err(format!("Unknown task [{}]", m.0));
+                                return Err(IpcError::UnknownTask { task: m.0 });
                             };
                             if is_task_started(task) {
                                 return Ok(rr(()));
                             }
                             task.started_waiters.borrow_mut().push(notify_tx);
                         }
-                        if notify_rx.await.map_err(|e| e.to_string())? {
+                        if notify_rx.await.map_err(|e| IpcError::Internal { message: e.to_string() })? {
                             return Ok(rr(()));
                         } else {
-                            return Err("Start canceled; task is now stopping".to_string());
+                            return Err(IpcError::StartCanceled { task: m.0 });
                         }
                     },
                     ipc::ServerReq::TaskWaitStopped(rr, m) => {
@@ -474,7 +901,7 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                         {
                             let state_dynamic = state.dynamic.lock().unwrap();
                             let Some(task) = maybe_get_task(&state_dynamic, &m.0) else {
-                                return Err(format!("Unknown task [{}]", m.0));
+                                return Err(IpcError::UnknownTask { task: m.0 });
                             };
                             if is_task_stopped(task) {
                                 return Ok(rr(()));
@@ -486,14 +913,18 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                                 if res {
                                     return Ok(rr(()));
                                 } else {
-                                    return Err("Stop canceled; task is now starting".to_string());
+                                    return Err(IpcError::StopCanceled { task: m.0 });
                                 }
                             },
                             Err(e) => {
-                                return Err(e.to_string());
+                                return Err(IpcError::Internal { message: e.to_string() });
                             },
                         }
                     },
+                    ipc::ServerReq::TaskWait(rr, m) => {
+                        wait_tasks(&state, m.tasks, m.state, m.any, m.timeout.map(|x| x.into())).await?;
+                        return Ok(rr(()));
+                    },
                     ipc::ServerReq::TaskListUserOn(rr, _m) => {
                         let state_dynamic = state.dynamic.lock().unwrap();
                         let mut out = vec![];
@@ -507,7 +938,7 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                     ipc::ServerReq::TaskListUpstream(rr, m) => {
                         let state_dynamic = state.dynamic.lock().unwrap();
                         if !state_dynamic.tasks.contains_key(&m.0) {
-                            return Err(format!("Unknown task [{}]", m.0));
+                            return Err(IpcError::UnknownTask { task: m.0 });
                         }
                         let mut out_stack = vec![];
                         let mut root = None;
@@ -523,14 +954,18 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                                     dependency_type: dependency_type,
                                     related: HashMap::new(),
                                 };
-                                walk_task_upstream(task, |upstream| {
-                                    for (next_id, next_dep_type) in upstream {
-                                        frontier.push((true, next_id.clone(), match dependency_type {
-                                            DependencyType::Strong => *next_dep_type,
-                                            DependencyType::Weak => DependencyType::Weak,
-                                        }));
-                                    }
-                                });
+                                if dependency_type != DependencyType::Conflicts {
+                                    // Conflicting task's dependencies aren't related
+                                    walk_task_upstream(task, |upstream| {
+                                        for (next_id, next_dep_type) in upstream {
+                                            frontier.push((true, next_id.clone(), match (dependency_type, next_dep_type) {
+                                                (_, DependencyType::Conflicts) => DependencyType::Conflicts,
+                                                (DependencyType::Weak, _) => DependencyType::Weak,
+                                                _ => *next_dep_type,
+                                            }));
+                                        }
+                                    });
+                                }
                                 out_stack.push((task_id, push_status));
                             } else {
                                 let (top_id, top) = out_stack.pop().unwrap();
@@ -546,7 +981,7 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                     ipc::ServerReq::TaskListDownstream(rr, m) => {
                         let state_dynamic = state.dynamic.lock().unwrap();
                         if !state_dynamic.tasks.contains_key(&m.0) {
-                            return Err(format!("Unknown task [{}]", m.0));
+                            return Err(IpcError::UnknownTask { task: m.0 });
                         }
                         let mut out_stack = vec![];
                         let mut root = None;
@@ -562,8 +997,11 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                                     dependency_type: dependency_type,
                                     related: HashMap::new(),
                                 };
-                                for (down_id, down_type) in task.downstream.borrow().iter() {
-                                    frontier.push((true, down_id.clone(), *down_type));
+                                if dependency_type != DependencyType::Conflicts {
+                                    // Conflicting task's dependents aren't related
+                                    for (down_id, down_type) in task.downstream.borrow().iter() {
+                                        frontier.push((true, down_id.clone(), *down_type));
+                                    }
                                 }
                                 out_stack.push((task_id, push_status));
                             } else {
@@ -577,21 +1015,188 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                         }
                         return Ok(rr(root.unwrap()));
                     },
-                    ipc::ServerReq::DemonListSchedule(rr, _m) => {
+                    ipc::ServerReq::TaskPlan(rr, m) => {
                         let state_dynamic = state.dynamic.lock().unwrap();
+                        if !state_dynamic.tasks.contains_key(&m.task) {
+                            return Err(IpcError::UnknownTask { task: m.task });
+                        }
+                        let dry_run = plan_dry_run(&state_dynamic, &m.task, m.on);
+                        let sorted = |ids: HashSet<TaskId>| {
+                            let mut ids = ids.into_iter().collect::<Vec<_>>();
+                            ids.sort();
+                            return ids;
+                        };
+                        let (transitive_on, transitive_off) =
+                            dry_run
+                                .transitive_on_changed
+                                .into_iter()
+                                .partition::<HashSet<_>, _>(|id| !get_task(&state_dynamic, id).transitive_on.get().0);
+                        return Ok(rr(RespTaskPlan {
+                            start: sorted(dry_run.plan.start),
+                            stop: sorted(dry_run.plan.stop),
+                            started: sorted(dry_run.plan.log_started),
+                            stopped: sorted(dry_run.plan.log_stopped),
+                            turned_off: sorted(dry_run.direct_on_changed),
+                            transitive_on: sorted(transitive_on),
+                            transitive_off: sorted(transitive_off),
+                        }));
+                    },
+                    ipc::ServerReq::TaskWhy(rr, m) => {
+                        let state_dynamic = state.dynamic.lock().unwrap();
+                        let Some(task) = maybe_get_task(&state_dynamic, &m.0) else {
+                            return Err(IpcError::UnknownTask { task: m.0 });
+                        };
+
+                        // # Find direct-on downstream tasks holding this on, breadth first for the
+                        //   shortest chains
+                        let mut transitive_on_via = vec![];
+                        if task.transitive_on.get().0 {
+                            let mut seen = HashSet::new();
+                            let mut frontier = VecDeque::from([vec![m.0.clone()]]);
+                            while let Some(chain) = frontier.pop_front() {
+                                let chain_task = get_task(&state_dynamic, chain.last().unwrap());
+                                for (downstream_id, downstream_type) in chain_task.downstream.borrow().iter() {
+                                    if *downstream_type != DependencyType::Strong {
+                                        continue;
+                                    }
+                                    if !seen.insert(downstream_id.clone()) {
+                                        continue;
+                                    }
+                                    let downstream = get_task(&state_dynamic, downstream_id);
+                                    if !is_task_on(downstream) {
+                                        continue;
+                                    }
+                                    let mut next_chain = chain.clone();
+                                    next_chain.push(downstream_id.clone());
+                                    if downstream.direct_on.get().0 {
+                                        transitive_on_via.push(next_chain.iter().rev().cloned().collect::<Vec<_>>());
+                                    }
+                                    frontier.push_back(next_chain);
+                                }
+                            }
+                            transitive_on_via.sort();
+                        }
+
+                        // # Find what's preventing the task from starting
+                        let mut blocked_by = vec![];
+                        if is_task_on(task) && !is_task_started(task) {
+                            let blocker = |task_id: &TaskId, dependency_type: DependencyType, group: Option<&String>| {
+                                let other = maybe_get_task(&state_dynamic, task_id);
+                                return WhyBlocker {
+                                    task: task_id.clone(),
+                                    dependency_type: dependency_type,
+                                    group: group.cloned(),
+                                    on: other.map(is_task_on).unwrap_or(false),
+                                    state: other.map(get_task_state),
+                                };
+                            };
+                            let (upstream, upstream_any) = get_task_upstream(task);
+                            for (upstream_id, dependency_type) in upstream {
+                                if !is_upstream_satisfied(&state_dynamic, upstream_id, *dependency_type, None) {
+                                    blocked_by.push(blocker(upstream_id, *dependency_type, None));
+                                }
+                            }
+                            for (group, members) in upstream_any {
+                                if members
+                                    .iter()
+                                    .any(|(id, t)| is_upstream_satisfied(&state_dynamic, id, *t, None)) {
+                                    continue;
+                                }
+                                for (upstream_id, dependency_type) in members {
+                                    blocked_by.push(blocker(upstream_id, *dependency_type, Some(group)));
+                                }
+                            }
+                            for other_id in get_conflicting_tasks(task) {
+                                let Some(other) = maybe_get_task(&state_dynamic, &other_id) else {
+                                    continue;
+                                };
+                                if !is_task_stopped(other) {
+                                    blocked_by.push(blocker(&other_id, DependencyType::Conflicts, None));
+                                }
+                            }
+                            blocked_by.sort_by(|a, b| a.task.cmp(&b.task));
+                        }
+                        return Ok(rr(RespTaskWhy {
+                            direct_on: task.direct_on.get().0,
+                            direct_on_reason: task.direct_on_reason.borrow().clone(),
+                            transitive_on: task.transitive_on.get().0,
+                            transitive_on_via: transitive_on_via,
+                            state: get_task_state(task),
+                            blocked_by: blocked_by,
+                        }));
+                    },
+                    ipc::ServerReq::TaskGraph(rr, _m) => {
+                        let state_dynamic = state.dynamic.lock().unwrap();
+                        let mut nodes = BTreeMap::new();
+                        let mut edges = vec![];
+                        for (task_id, task) in &state_dynamic.tasks {
+                            let task = &state_dynamic.task_alloc[*task];
+                            nodes.insert(task_id.clone(), GraphNode {
+                                task_type: match &task.specific {
+                                    TaskStateSpecific::Empty(_) => GraphNodeType::Empty,
+                                    TaskStateSpecific::Long(_) => GraphNodeType::Long,
+                                    TaskStateSpecific::Short(_) => GraphNodeType::Short,
+                                },
+                                on: Some(is_task_on(task)),
+                                state: Some(get_task_state(task)),
+                            });
+                            for (downstream_id, dependency_type) in task.downstream.borrow().iter() {
+                                let (_, downstream_upstream_any) =
+                                    get_task_upstream(get_task(&state_dynamic, downstream_id));
+                                edges.push(GraphEdge {
+                                    downstream: downstream_id.clone(),
+                                    upstream: task_id.clone(),
+                                    dependency_type: *dependency_type,
+                                    group: downstream_upstream_any
+                                        .iter()
+                                        .find(|(_, members)| members.contains_key(task_id))
+                                        .map(|(group, _)| group.clone()),
+                                });
+                            }
+                        }
+                        edges.sort();
+                        return Ok(rr(RespTaskGraph {
+                            nodes: nodes,
+                            edges: edges,
+                        }));
+                    },
+                    ipc::ServerReq::DemonListSchedule(rr, m) => {
+                        let state_dynamic = state.dynamic.lock().unwrap();
+                        if let Some(task_id) = &m.task {
+                            if !state_dynamic.tasks.contains_key(task_id) {
+                                return Err(IpcError::UnknownTask { task: task_id.clone() });
+                            }
+                        }
                         let instant_now = Instant::now();
                         let now = Utc::now();
                         let mut out = vec![];
-                        out.reserve(state_dynamic.schedule.len() + 1);
-                        #[allow(for_loops_over_fallibles)]
-                        for (at, entry) in &state_dynamic.schedule_top {
-                            let at_secs: i64 = match at.duration_since(instant_now).as_secs().try_into() {
-                                Ok(s) => s,
+                        for (at, entry) in state_dynamic
+                            .schedule_top
+                            .iter()
+                            .map(|(at, entry)| (at, entry))
+                            .chain(
+                                state_dynamic
+                                    .schedule
+                                    .iter()
+                                    .flat_map(|(at, entries)| entries.iter().map(move |entry| (at, entry))),
+                            ) {
+                            if let Some(task_id) = &m.task {
+                                if entry.0 != *task_id {
+                                    continue;
+                                }
+                            }
+                            if let Some(limit) = m.limit {
+                                if out.len() >= limit {
+                                    break;
+                                }
+                            }
+                            let until = match chrono::Duration::from_std(at.saturating_duration_since(instant_now)) {
+                                Ok(d) => d,
                                 Err(e) => {
                                     log.log_err(
                                         loga::WARN,
                                         e.context_with(
-                                            "Schedule entry out of i64 range for chrono IPC response",
+                                            "Schedule entry out of range for chrono IPC response",
                                             ea!(task = entry.0, rule = entry.1.dbg_str()),
                                         ),
                                     );
@@ -599,33 +1204,25 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                                 },
                             };
                             out.push(RespScheduleEntry {
-                                at: now + chrono::Duration::seconds(at_secs),
+                                at: now + until,
                                 task: entry.0.clone(),
                                 rule: entry.1.clone(),
+                                on: entry.2,
                             });
                         }
-                        for (at, entries) in &state_dynamic.schedule {
-                            for entry in entries {
-                                let at_secs: i64 = match at.duration_since(instant_now).as_secs().try_into() {
-                                    Ok(s) => s,
-                                    Err(e) => {
-                                        log.log_err(
-                                            loga::WARN,
-                                            e.context_with(
-                                                "Schedule entry out of i64 range for chrono IPC response",
-                                                ea!(task = entry.0, rule = entry.1.dbg_str()),
-                                            ),
-                                        );
-                                        continue;
-                                    },
-                                };
-                                out.push(RespScheduleEntry {
-                                    at: now + chrono::Duration::seconds(at_secs),
-                                    task: entry.0.clone(),
-                                    rule: entry.1.clone(),
-                                });
-                            }
-                        }
+                        return Ok(rr(out));
+                    },
+                    ipc::ServerReq::DemonAuditLog(rr, m) => {
+                        let state_dynamic = state.dynamic.lock().unwrap();
+                        let mut out = state_dynamic
+                            .audit_log
+                            .iter()
+                            .rev()
+                            .filter(|e| m.task.as_ref().map(|t| *t == e.task).unwrap_or(true))
+                            .take(m.limit.unwrap_or(usize::MAX))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        out.reverse();
                         return Ok(rr(out));
                     },
                     ipc::ServerReq::DemonEnv(rr, _m) => {
@@ -636,7 +1233,7 @@ async fn handle_ipc(state: Arc<State>, mut conn: ipc::ServerConn) {
                     },
                 }
             }
-        }.await.unwrap_or_else(ServerResp::err);
+        }.await.unwrap_or_else(|e| ServerResp::err(encode_ipc_error(&e)));
         match conn.send_resp(resp).await {
             Ok(_) => { },
             Err(e) => {
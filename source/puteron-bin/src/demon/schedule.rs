@@ -1,6 +1,15 @@
 use {
-    super::state::StateDynamic,
-    crate::demon::state::TaskStateSpecific,
+    super::{
+        state::{
+            State,
+            StateDynamic,
+        },
+        task_util::{
+            get_task_labels,
+            get_task_schedule,
+            maybe_get_task,
+        },
+    },
     chrono::{
         DateTime,
         Datelike,
@@ -24,7 +33,9 @@ use {
     tokio::time::Instant,
 };
 
-pub(crate) type ScheduleRule = Arc<(TaskId, interface::task::schedule::Rule)>;
+/// Task, rule, and whether the task is turned on (vs off, at the end of a window).
+/// For windows the rule is the `on` or `off` rule rather than the window itself.
+pub(crate) type ScheduleRule = Arc<(TaskId, interface::task::schedule::Rule, bool)>;
 pub(crate) type ScheduleDynamic = BTreeMap<Instant, Vec<ScheduleRule>>;
 
 pub fn calc_next_instant(
@@ -84,6 +95,10 @@ pub fn calc_next_instant(
                 next = next.checked_add_months(Months::new(1)).unwrap();
             }
         },
+        interface::task::schedule::Rule::Window(s) => {
+            // Windows are scheduled as separate on and off entries, this is a fallback
+            return calc_next_instant(now, instant_now, &s.on, initial);
+        },
         interface::task::schedule::Rule::Yearly(s) => {
             let next1 = now.date_naive().with_month(s.month.0.number_from_month()).unwrap();
             next = match next1.with_day(s.day as u32) {
@@ -98,23 +113,139 @@ pub fn calc_next_instant(
     return instant_now + (next - now).to_std().unwrap();
 }
 
-pub(crate) fn populate_schedule(state_dynamic: &mut StateDynamic) {
-    for (id, task) in &state_dynamic.tasks {
-        let task = &state_dynamic.task_alloc[*task];
-        if let TaskStateSpecific::Short(t) = &task.specific {
-            for rule in &t.spec.schedule {
-                state_dynamic
-                    .schedule
-                    .entry(calc_next_instant(Utc::now(), Instant::now(), rule, false))
-                    .or_default()
-                    .push(ScheduleRule::new((id.clone(), rule.clone())));
+pub(crate) fn is_excluded(excludes: &[interface::task::schedule::Exclude], at: DateTime<Utc>) -> bool {
+    let date = at.date_naive();
+    for exclude in excludes {
+        match exclude {
+            interface::task::schedule::Exclude::DateRange(r) => {
+                if r.start <= date && date <= r.end {
+                    return true;
+                }
+            },
+            interface::task::schedule::Exclude::Weekday(w) => {
+                if date.weekday() == *w {
+                    return true;
+                }
+            },
+            interface::task::schedule::Exclude::Date(d) => {
+                if date == *d {
+                    return true;
+                }
+            },
+        }
+    }
+    return false;
+}
+
+/// Returns a reason if a scheduled turn-on of the task should be skipped at `now`
+/// (exclusions or maintenance windows).
+pub(crate) fn schedule_skip_reason(
+    state: &State,
+    state_dynamic: &StateDynamic,
+    task_id: &TaskId,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    let task = maybe_get_task(state_dynamic, task_id);
+    if let Some(task) = task {
+        let (_, exclude) = get_task_schedule(task);
+        if is_excluded(exclude, now) {
+            return Some("Excluded date".to_string());
+        }
+    }
+    for (name, window) in &state.maintenance_windows {
+        if now < window.start || now >= window.end {
+            continue;
+        }
+        if window.suppress_schedule.is_some() || !window.suppress_schedule_labels.is_empty() {
+            let listed = window.suppress_schedule.iter().flatten().any(|x| x == task_id);
+            let labelled =
+                task
+                    .map(|t| get_task_labels(t).iter().any(|l| window.suppress_schedule_labels.contains(l)))
+                    .unwrap_or(false);
+            if !listed && !labelled {
+                continue;
             }
         }
+        return Some(format!("In maintenance window [{}]", name));
+    }
+    return None;
+}
+
+/// Add entries for each of the task's rules to the schedule.
+pub(crate) fn schedule_task(
+    state_dynamic: &mut StateDynamic,
+    task_id: &TaskId,
+    rules: &[interface::task::schedule::Rule],
+    initial: bool,
+) {
+    if rules.is_empty() {
+        return;
+    }
+    for rule in rules {
+        let mut entries = vec![];
+        match rule {
+            interface::task::schedule::Rule::Window(w) => {
+                entries.push((w.on.as_ref(), true));
+                entries.push((w.off.as_ref(), false));
+            },
+            _ => {
+                entries.push((rule, true));
+            },
+        }
+        for (rule, on) in entries {
+            state_dynamic
+                .schedule
+                .entry(calc_next_instant(Utc::now(), Instant::now(), rule, initial))
+                .or_default()
+                .push(ScheduleRule::new((task_id.clone(), rule.clone(), on)));
+        }
+    }
+    state_dynamic.notify_reschedule.notify_one();
+}
+
+/// Whether the current time is within any of the window rules (the next `off` comes
+/// before the next `on`), i.e. the task should be on now.
+pub(crate) fn is_in_schedule_window(rules: &[interface::task::schedule::Rule]) -> bool {
+    let now = Utc::now();
+    let instant_now = Instant::now();
+    for rule in rules {
+        let interface::task::schedule::Rule::Window(w) = rule else {
+            continue;
+        };
+        if calc_next_instant(now, instant_now, &w.off, false) < calc_next_instant(now, instant_now, &w.on, false) {
+            return true;
+        }
+    }
+    return false;
+}
+
+/// Remove all of the task's entries from the schedule, including the one currently
+/// being waited on.
+pub(crate) fn unschedule_task(state_dynamic: &mut StateDynamic, task_id: &TaskId) {
+    let mut modified = false;
+    state_dynamic.schedule.retain(|_, v| {
+        v.retain(|r| {
+            let keep = r.0 != *task_id;
+            if !keep {
+                modified = true;
+            }
+            return keep;
+        });
+        return !v.is_empty();
+    });
+    if state_dynamic.schedule_top.as_ref().filter(|(_, top)| top.0 == *task_id).is_some() {
+        // Invalidate, so the main loop won't fire or restore it
+        state_dynamic.schedule_top = None;
+        modified = true;
+    }
+    if modified {
+        state_dynamic.notify_reschedule.notify_one();
     }
 }
 
 pub(crate) fn pop_schedule(state_dynamic: &mut StateDynamic) -> Option<(Instant, ScheduleRule)> {
     let Some(mut next_entry) = state_dynamic.schedule.first_entry() else {
+        state_dynamic.schedule_top = None;
         return None;
     };
     let instant = next_entry.key().clone();
@@ -0,0 +1,126 @@
+#![cfg(test)]
+
+use {
+    super::{
+        maintenance::{
+            get_window_turn_off_tasks,
+            maintenance_end,
+            maintenance_start,
+        },
+        schedule::is_excluded,
+        state::TaskStateSpecific,
+        task_execute::{
+            set_task_user_off,
+            set_task_user_on,
+        },
+        task_plan_test::{
+            build_state,
+            build_test_state,
+            task_empty,
+        },
+        task_util::{
+            control_reason,
+            get_task,
+        },
+    },
+    chrono::{
+        DateTime,
+        NaiveDate,
+        Utc,
+        Weekday,
+    },
+    puteron::interface::{
+        demon::MaintenanceWindow,
+        ipc::ControlSource,
+        task::schedule::{
+            Exclude,
+            ExcludeDateRange,
+        },
+    },
+    std::sync::Arc,
+};
+
+fn at(s: &str) -> DateTime<Utc> {
+    return s.parse().unwrap();
+}
+
+fn date(s: &str) -> NaiveDate {
+    return s.parse().unwrap();
+}
+
+#[test]
+fn exclude_none() {
+    assert!(!is_excluded(&[], at("2026-12-25T12:00:00Z")));
+}
+
+#[test]
+fn exclude_date() {
+    let excludes = [Exclude::Date(date("2026-12-25"))];
+    assert!(is_excluded(&excludes, at("2026-12-25T00:00:00Z")));
+    assert!(is_excluded(&excludes, at("2026-12-25T23:59:59Z")));
+    assert!(!is_excluded(&excludes, at("2026-12-26T00:00:00Z")));
+}
+
+#[test]
+fn exclude_weekday() {
+    let excludes = [Exclude::Weekday(Weekday::Sun)];
+
+    // Sunday
+    assert!(is_excluded(&excludes, at("2026-10-18T08:00:00Z")));
+    assert!(!is_excluded(&excludes, at("2026-10-19T08:00:00Z")));
+}
+
+#[test]
+fn exclude_date_range_inclusive() {
+    let excludes = [Exclude::DateRange(ExcludeDateRange {
+        start: date("2026-12-20"),
+        end: date("2027-01-03"),
+    })];
+    assert!(!is_excluded(&excludes, at("2026-12-19T23:59:59Z")));
+    assert!(is_excluded(&excludes, at("2026-12-20T00:00:00Z")));
+    assert!(is_excluded(&excludes, at("2027-01-01T12:00:00Z")));
+    assert!(is_excluded(&excludes, at("2027-01-03T23:59:59Z")));
+    assert!(!is_excluded(&excludes, at("2027-01-04T00:00:00Z")));
+}
+
+#[test]
+fn window_turn_off_labels() {
+    let mut a = task_empty("a", true, []);
+    let TaskStateSpecific::Empty(s) = &mut a.specific else {
+        unreachable!();
+    };
+    s.spec.labels = vec!["batch".to_string()];
+    let state_dynamic = build_state([a, task_empty("b", true, []), task_empty("c", true, [])]);
+    let got = get_window_turn_off_tasks(&state_dynamic, &MaintenanceWindow {
+        start: at("2026-12-20T00:00:00Z"),
+        end: at("2027-01-03T00:00:00Z"),
+        suppress_schedule: None,
+        suppress_schedule_labels: vec![],
+        turn_off: vec!["b".to_string(), "missing".to_string()],
+        turn_off_labels: vec!["batch".to_string()],
+    });
+    assert_eq!(got.into_iter().collect::<Vec<_>>(), vec!["a".to_string(), "b".to_string(), "missing".to_string()]);
+}
+
+#[test]
+fn window_end_skips_changed_tasks() {
+    let mut state = build_test_state(build_state([task_empty("a", true, []), task_empty("b", true, [])]));
+    Arc::get_mut(&mut state).unwrap().maintenance_windows.insert("freeze".to_string(), MaintenanceWindow {
+        start: at("2026-12-20T00:00:00Z"),
+        end: at("2027-01-03T00:00:00Z"),
+        suppress_schedule: None,
+        suppress_schedule_labels: vec![],
+        turn_off: vec!["a".to_string(), "b".to_string()],
+        turn_off_labels: vec![],
+    });
+    let name = "freeze".to_string();
+    let mut state_dynamic = state.dynamic.lock().unwrap();
+    maintenance_start(&state, &mut state_dynamic, &name);
+
+    // User turns b on and back off during the window
+    set_task_user_on(&state, &mut state_dynamic, &"b".to_string(), control_reason(ControlSource::User));
+    set_task_user_off(&state, &mut state_dynamic, &"b".to_string(), control_reason(ControlSource::User));
+    maintenance_end(&state, &mut state_dynamic, &name);
+    assert!(get_task(&state_dynamic, &"a".to_string()).direct_on.get().0);
+    assert!(!get_task(&state_dynamic, &"b".to_string()).direct_on.get().0);
+}
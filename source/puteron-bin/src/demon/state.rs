@@ -11,7 +11,11 @@ use {
     puteron::interface::{
         self,
         base::TaskId,
-        ipc::ProcState,
+        ipc::{
+            AuditEntry,
+            ControlReason,
+            ProcState,
+        },
         task::DependencyType,
     },
     slotmap::{
@@ -23,7 +27,11 @@ use {
             Cell,
             RefCell,
         },
-        collections::HashMap,
+        collections::{
+            HashMap,
+            HashSet,
+            VecDeque,
+        },
         path::PathBuf,
         sync::{
             Arc,
@@ -57,6 +65,9 @@ pub(crate) struct TaskStateShort {
     pub(crate) state: Cell<(ProcState, DateTime<Utc>)>,
     pub(crate) pid: Cell<Option<i32>>,
     pub(crate) failed_start_count: Cell<usize>,
+    // Run was triggered manually as if by the schedule (run-now), cleared once the
+    // run finishes
+    pub(crate) triggered: Cell<bool>,
     pub(crate) stop: RefCell<Option<oneshot::Sender<()>>>,
     pub(crate) spec: interface::task::TaskSpecShort,
 }
@@ -70,6 +81,8 @@ pub(crate) enum TaskStateSpecific {
 pub(crate) struct TaskState_ {
     pub(crate) id: TaskId,
     pub(crate) direct_on: Cell<(bool, DateTime<Utc>)>,
+    /// What last changed `direct_on`
+    pub(crate) direct_on_reason: RefCell<Option<ControlReason>>,
     pub(crate) transitive_on: Cell<(bool, DateTime<Utc>)>,
     pub(crate) downstream: RefCell<HashMap<TaskId, DependencyType>>,
     pub(crate) specific: TaskStateSpecific,
@@ -89,12 +102,39 @@ pub(crate) struct StateDynamic {
     pub(crate) schedule_top: Option<(Instant, ScheduleRule)>,
     pub(crate) schedule: ScheduleDynamic,
     pub(crate) notify_reschedule: Arc<Notify>,
+    // Tasks to turn back on at the end of each active maintenance window
+    pub(crate) maintenance_restore: HashMap<String, Vec<TaskId>>,
+    // Template specs (ids ending in `@`), instantiated when an instance is turned on
+    pub(crate) templates: HashMap<TaskId, interface::task::Task>,
+    // Instances created on demand from templates, deleted when no longer used
+    pub(crate) instances: HashSet<TaskId>,
+    // Recent `direct_on` changes, oldest first, at most `AUDIT_LOG_SIZE`
+    pub(crate) audit_log: VecDeque<AuditEntry>,
+    // New specs for tasks being replaced with `restart`, swapped in once the task
+    // stops
+    pub(crate) replacements: HashMap<TaskId, interface::task::Task>,
 }
 
 pub(crate) struct State {
     pub(crate) log: Log,
     pub(crate) task_dirs: Vec<PathBuf>,
     pub(crate) env: HashMap<String, String>,
+    // Loaded each time a task is started
+    pub(crate) env_files: Vec<interface::task::EnvironmentFile>,
+    pub(crate) maintenance_windows: HashMap<String, interface::demon::MaintenanceWindow>,
+    // Config `variables` for substitution in task specs
+    pub(crate) variables: HashMap<String, String>,
+    // Base directories, for variables and managed directories
+    pub(crate) runtime_dir: PathBuf,
+    pub(crate) state_dir: PathBuf,
+    pub(crate) cache_dir: PathBuf,
+    pub(crate) logs_dir: PathBuf,
+    pub(crate) access: Option<interface::demon::Access>,
+    // User and group names for access lists, loaded at startup
+    pub(crate) user_ids: HashMap<String, u32>,
+    pub(crate) group_ids: HashMap<String, u32>,
     pub(crate) dynamic: Mutex<StateDynamic>,
+    // Notified whenever any task changes state
+    pub(crate) notify_state: Notify,
     pub(crate) tokio_tasks: TaskTracker,
 }
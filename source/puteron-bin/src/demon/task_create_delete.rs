@@ -1,10 +1,12 @@
 use {
     super::{
+        access::get_unknown_access_names,
         schedule::{
-            calc_next_instant,
-            ScheduleRule,
+            schedule_task,
+            unschedule_task,
         },
         state::{
+            State,
             StateDynamic,
             TaskStateEmpty,
             TaskStateLong,
@@ -13,47 +15,231 @@ use {
             TaskState_,
         },
         task_util::{
+            get_managed_directories,
+            get_missing_upstream,
+            get_spec_allow_control,
+            get_spec_upstream,
             get_task,
+            get_task_schedule,
+            get_task_spec,
+            get_task_upstream,
+            is_task_on,
+            is_task_stopped,
+            managed_directory_path,
             maybe_get_task,
+            substitute_task_command,
+            substitute_task_path,
+            substitute_task_string,
+            validate_managed_directory,
             walk_task_upstream,
+            RESERVED_RUNTIME_NAMES,
         },
     },
-    chrono::Utc,
+    crate::spec::instantiate_template,
+    chrono::{
+        DateTime,
+        Utc,
+    },
     puteron::interface::{
         self,
-        base::TaskId,
-        ipc::ProcState,
-        task::Task,
+        base::{
+            split_instance_id,
+            TaskId,
+        },
+        ipc::{
+            ControlReason,
+            ProcState,
+        },
+        task::{
+            DependencyType,
+            Task,
+        },
     },
-    std::cell::{
-        Cell,
-        RefCell,
+    std::{
+        cell::{
+            Cell,
+            RefCell,
+        },
+        collections::{
+            BTreeMap,
+            HashMap,
+            HashSet,
+        },
+        path::{
+            Component,
+            Path,
+        },
     },
-    tokio::time::Instant,
 };
 
 pub(crate) fn validate_new_task(
+    state: &State,
     state_dynamic: &StateDynamic,
     errors: &mut Vec<loga::Error>,
     task_id: &TaskId,
     task: &interface::task::Task,
 ) {
-    let upstream: Vec<&String> = match task {
-        Task::Empty(s) => {
-            s.upstream.keys().collect()
-        },
-        Task::Long(s) => {
-            s.upstream.keys().collect()
-        },
-        Task::Short(s) => {
-            s.upstream.keys().collect()
-        },
+    // Ids are used as file names (managed directories, credentials)
+    if task_id.is_empty() || task_id.contains('/') || task_id.contains("..") || task_id.contains('\0') {
+        errors.push(loga::err(format!("Task id [{}] must not be empty or contain `/`, `..` or NUL", task_id)));
+    }
+    for unknown in get_unknown_access_names(state, get_spec_allow_control(task)) {
+        errors.push(loga::err(format!("Task [{}] allow_control refers to unknown {}", task_id, unknown)));
+    }
+    let upstream = get_spec_upstream(task);
+    let (plain_upstream, upstream_any) = match task {
+        Task::Empty(s) => (&s.upstream, &s.upstream_any),
+        Task::Long(s) => (&s.upstream, &s.upstream_any),
+        Task::Short(s) => (&s.upstream, &s.upstream_any),
     };
-    for upstream_id in upstream {
+    let mut seen_upstream = plain_upstream.keys().collect::<HashSet<_>>();
+    for (group_name, group) in upstream_any {
+        if group.is_empty() {
+            errors.push(loga::err(format!("Task [{}] upstream group [{}] is empty", task_id, group_name)));
+        }
+        for (upstream_id, upstream_type) in group {
+            match upstream_type {
+                DependencyType::Strong | DependencyType::Weak => { },
+                DependencyType::After | DependencyType::Conflicts => {
+                    errors.push(
+                        loga::err(
+                            format!(
+                                "Task [{}] upstream group [{}] member [{}] must be `strong` or `weak`",
+                                task_id,
+                                group_name,
+                                upstream_id
+                            ),
+                        ),
+                    );
+                },
+            }
+            if !seen_upstream.insert(upstream_id) {
+                errors.push(
+                    loga::err(
+                        format!("Task [{}] lists upstream [{}] more than once (including groups)", task_id, upstream_id),
+                    ),
+                );
+            }
+        }
+    }
+    let command = match task {
+        Task::Empty(_) => None,
+        Task::Long(s) => Some(&s.command),
+        Task::Short(s) => Some(&s.command),
+    };
+    if let Some(command) = command {
+        if let Err(e) = substitute_task_command(state, task_id, command) {
+            errors.push(e.context(format!("Task [{}] has an invalid variable reference", task_id)));
+        }
+        for (env_name, base, dir) in get_managed_directories(state, command) {
+            if let Err(e) = validate_managed_directory(dir) {
+                errors.push(e.context(format!("Task [{}] has an invalid managed directory ({})", task_id, env_name)));
+            }
+            if base == state.runtime_dir {
+                if let Some(Component::Normal(first)) =
+                    managed_directory_path(Path::new(""), task_id, dir).components().next() {
+                    if RESERVED_RUNTIME_NAMES.iter().any(|x| first == *x) {
+                        errors.push(
+                            loga::err(
+                                format!(
+                                    "Task [{}] managed directory ({}) would be at [{}] in the runtime directory, which is reserved for puteron",
+                                    task_id,
+                                    env_name,
+                                    first.to_string_lossy()
+                                ),
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        for name in command.credentials.keys() {
+            if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                errors.push(
+                    loga::err(
+                        format!("Task [{}] has invalid credential name [{}], must be a plain file name", task_id, name),
+                    ),
+                );
+            }
+        }
+    }
+    if let Task::Long(s) = task {
+        if let Some(interface::task::StartedCheck::Path(p)) = &s.started_check {
+            if let Err(e) = substitute_task_path(state, task_id, p) {
+                errors.push(
+                    e.context(format!("Task [{}] has an invalid variable reference in its started check", task_id)),
+                );
+            }
+        }
+        if s.reload_signal.is_some() && s.reload_command.is_some() {
+            errors.push(loga::err(format!("Task [{}] has both a reload signal and a reload command", task_id)));
+        }
+        if let Some(line) = &s.reload_command {
+            if line.is_empty() {
+                errors.push(loga::err(format!("Task [{}] has an empty reload command", task_id)));
+            }
+            for arg in line {
+                if let Err(e) = substitute_task_string(state, task_id, arg) {
+                    errors.push(
+                        e.context(format!("Task [{}] has an invalid variable reference in its reload command", task_id)),
+                    );
+                }
+            }
+        }
+    }
+    let (schedule, allow_plain_rules) = match task {
+        Task::Empty(s) => (&s.schedule, false),
+        Task::Long(s) => (&s.schedule, false),
+        Task::Short(s) => (&s.schedule, true),
+    };
+    for rule in schedule {
+        match rule {
+            interface::task::schedule::Rule::Window(w) => {
+                for sub_rule in [&w.on, &w.off] {
+                    if let interface::task::schedule::Rule::Window(_) = sub_rule.as_ref() {
+                        errors.push(
+                            loga::err(format!("Task [{}] has a schedule window nested in another window", task_id)),
+                        );
+                    }
+                }
+            },
+            _ => {
+                if !allow_plain_rules {
+                    errors.push(
+                        loga::err(
+                            format!(
+                                "Task [{}] has a schedule rule other than `window`, which is only allowed for short tasks",
+                                task_id
+                            ),
+                        ),
+                    );
+                }
+            },
+        }
+    }
+    for (upstream_id, upstream_type) in &upstream {
         let Some(upstream_task) = maybe_get_task(&state_dynamic, &upstream_id) else {
             errors.push(loga::err(format!("Task [{}] has missing upstream [{}]", task_id, upstream_id)));
             continue;
         };
+        if **upstream_type == DependencyType::After {
+            // Satisfied once the upstream turns off
+            continue;
+        }
+        if **upstream_type == DependencyType::Conflicts {
+            if get_other_conflicting_tasks(upstream_task, task_id).contains(task_id) {
+                errors.push(
+                    loga::err(
+                        format!(
+                            "Task [{}] and [{}] both declare they conflict with each other, conflicts should only be declared on one side",
+                            task_id,
+                            upstream_id
+                        ),
+                    ),
+                );
+            }
+            continue;
+        }
         match &upstream_task.specific {
             TaskStateSpecific::Empty(_s) => { },
             TaskStateSpecific::Long(_s) => { },
@@ -88,30 +274,264 @@ pub(crate) fn validate_new_task(
             },
         }
     }
+    // Only one member of each group needs to start, so groups don't contribute to
+    // the required set
+    validate_new_task_conflicts(state_dynamic, errors, task_id, &plain_upstream.iter().collect::<Vec<_>>());
+}
+
+/// Like `get_conflicting_tasks`, but ignoring conflicts declared by `task_id`'s
+/// current spec. When a task is replaced in place its old edges are still there
+/// during validation, but only the new spec's conflicts apply.
+fn get_other_conflicting_tasks(task: &TaskState_, task_id: &TaskId) -> Vec<TaskId> {
+    let mut out = vec![];
+    walk_task_upstream(task, |upstream| {
+        for (upstream_id, upstream_type) in upstream {
+            if *upstream_type == DependencyType::Conflicts {
+                out.push(upstream_id.clone());
+            }
+        }
+    });
+    for (downstream_id, downstream_type) in task.downstream.borrow().iter() {
+        if *downstream_type == DependencyType::Conflicts && downstream_id != task_id {
+            out.push(downstream_id.clone());
+        }
+    }
+    return out;
+}
+
+/// Check that none of the tasks that need to be started for this task to start
+/// (including the task itself) conflict with each other.
+fn validate_new_task_conflicts(
+    state_dynamic: &StateDynamic,
+    errors: &mut Vec<loga::Error>,
+    task_id: &TaskId,
+    upstream: &[(&String, &DependencyType)],
+) {
+    // Everything that must be started for the new task to start, and which task
+    // requires it
+    let mut required = HashMap::new();
+    required.insert(task_id.clone(), task_id.clone());
+    let mut frontier = vec![];
+    for (upstream_id, upstream_type) in upstream {
+        match upstream_type {
+            DependencyType::Strong | DependencyType::Weak => {
+                frontier.push(((*upstream_id).clone(), task_id.clone()));
+            },
+            DependencyType::After | DependencyType::Conflicts => { },
+        }
+    }
+    while let Some((required_id, required_by)) = frontier.pop() {
+        if required.contains_key(&required_id) {
+            continue;
+        }
+        let Some(required_task) = maybe_get_task(state_dynamic, &required_id) else {
+            // Reported elsewhere
+            continue;
+        };
+        for (upstream_id, upstream_type) in get_task_upstream(required_task).0 {
+            match upstream_type {
+                DependencyType::Strong | DependencyType::Weak => {
+                    frontier.push((upstream_id.clone(), required_id.clone()));
+                },
+                DependencyType::After | DependencyType::Conflicts => { },
+            }
+        }
+        required.insert(required_id, required_by);
+    }
+
+    // Look for conflicts among them
+    let mut reported = HashSet::new();
+    for (required_id, required_by) in &required {
+        let conflicting = if required_id == task_id {
+            upstream
+                .iter()
+                .filter(|(_, t)| **t == DependencyType::Conflicts)
+                .map(|(id, _)| (*id).clone())
+                .collect::<Vec<_>>()
+        } else {
+            get_other_conflicting_tasks(get_task(state_dynamic, required_id), task_id)
+        };
+        for other_id in conflicting {
+            let Some(other_required_by) = required.get(&other_id) else {
+                continue;
+            };
+            let mut pair = [required_id.clone(), other_id.clone()];
+            pair.sort();
+            if !reported.insert(pair) {
+                continue;
+            }
+            errors.push(
+                loga::err(
+                    format!(
+                        "Task [{}] can never start: it requires [{}] (via [{}]) and [{}] (via [{}]) which conflict",
+                        task_id,
+                        required_id,
+                        required_by,
+                        other_id,
+                        other_required_by
+                    ),
+                ),
+            );
+        }
+    }
+}
+
+/// Check that `new_specs` (replacing any existing tasks with the same ids) don't
+/// introduce dependency cycles, including via template instances that would be
+/// created for them. Each cycle is reported with its full path. Missing upstreams
+/// are reported by `validate_new_task`.
+pub(crate) fn validate_task_graph(
+    state_dynamic: &StateDynamic,
+    new_specs: &BTreeMap<TaskId, Task>,
+    errors: &mut Vec<loga::Error>,
+) {
+    fn spec_upstream(spec: &Task) -> Vec<TaskId> {
+        return get_spec_upstream(spec)
+            .into_iter()
+            .filter(|(_, t)| **t != DependencyType::Conflicts)
+            .map(|(id, _)| id.clone())
+            .collect();
+    }
+
+    let get_upstream = |task_id: &TaskId| -> Vec<TaskId> {
+        if let Some(spec) = new_specs.get(task_id) {
+            return spec_upstream(spec);
+        }
+        if let Some(task) = maybe_get_task(state_dynamic, task_id) {
+            return walk_task_upstream(task, |upstream| {
+                upstream.filter(|(_, t)| **t != DependencyType::Conflicts).map(|(id, _)| id.clone()).collect()
+            });
+        }
+        if let Some((template_id, instance)) = split_instance_id(task_id) {
+            if let Some(template) = state_dynamic.templates.get(&template_id) {
+                return spec_upstream(&instantiate_template(template, instance));
+            }
+        }
+
+        // Missing
+        return vec![];
+    };
+
+    // Depth first from each new task. Anything cycle-free is marked done so shared
+    // upstreams are only walked once.
+    let mut done = HashSet::new();
+    for root_id in new_specs.keys() {
+        if done.contains(root_id) {
+            continue;
+        }
+        let mut path = vec![(root_id.clone(), get_upstream(root_id))];
+        loop {
+            let Some((_, remaining)) = path.last_mut() else {
+                break;
+            };
+            let Some(next_id) = remaining.pop() else {
+                let (id, _) = path.pop().unwrap();
+                done.insert(id);
+                continue;
+            };
+            if done.contains(&next_id) {
+                continue;
+            }
+            if let Some(i) = path.iter().position(|(id, _)| *id == next_id) {
+                errors.push(
+                    loga::err(
+                        format!(
+                            "Task [{}] has a dependency cycle: {}",
+                            next_id,
+                            path[i..]
+                                .iter()
+                                .map(|(id, _)| id.as_str())
+                                .chain([next_id.as_str()])
+                                .collect::<Vec<_>>()
+                                .join(" -> ")
+                        ),
+                    ),
+                );
+                continue;
+            }
+            let upstream = get_upstream(&next_id);
+            path.push((next_id, upstream));
+        }
+    }
+}
+
+/// Instance name used when checking templates.
+const TEMPLATE_CHECK_INSTANCE: &str = "check";
+
+/// Templates are only instantiated when used, so check an example instance
+/// instead. The template must already be in `state_dynamic.templates` so
+/// dependency cycles through its own instances are found. Upstream instances of
+/// templates are only checked for their template existing, they're validated
+/// themselves when created. Returns the missing (non-instance) upstreams.
+pub(crate) fn validate_new_template(
+    state: &State,
+    state_dynamic: &StateDynamic,
+    errors: &mut Vec<loga::Error>,
+    template_id: &TaskId,
+    spec: &Task,
+) -> Vec<TaskId> {
+    let instance_id = format!("{}{}", template_id, TEMPLATE_CHECK_INSTANCE);
+    let instance = instantiate_template(spec, TEMPLATE_CHECK_INSTANCE);
+    validate_task_graph(state_dynamic, &BTreeMap::from([(instance_id.clone(), instance.clone())]), errors);
+
+    // Set aside upstream instances
+    let mut plain_instance = instance;
+    let (upstream, upstream_any) = match &mut plain_instance {
+        Task::Empty(s) => (&mut s.upstream, &mut s.upstream_any),
+        Task::Long(s) => (&mut s.upstream, &mut s.upstream_any),
+        Task::Short(s) => (&mut s.upstream, &mut s.upstream_any),
+    };
+    let mut is_plain = |upstream_id: &TaskId| -> bool {
+        let Some((upstream_template_id, _)) = split_instance_id(upstream_id) else {
+            return true;
+        };
+        if state_dynamic.tasks.contains_key(upstream_id) {
+            return true;
+        }
+        if !state_dynamic.templates.contains_key(&upstream_template_id) {
+            errors.push(
+                loga::err(
+                    format!(
+                        "Template [{}] has upstream [{}] but there's no template [{}]",
+                        template_id,
+                        upstream_id,
+                        upstream_template_id
+                    ),
+                ),
+            );
+        }
+        return false;
+    };
+    upstream.retain(|id, _| is_plain(id));
+    upstream_any.retain(|_, group| {
+        if group.is_empty() {
+            // Reported as an error
+            return true;
+        }
+        group.retain(|id, _| is_plain(id));
+
+        // Drop groups of only instances
+        return !group.is_empty();
+    });
+    validate_new_task(state, state_dynamic, errors, &instance_id, &plain_instance);
+    return get_missing_upstream(state_dynamic, &plain_instance);
 }
 
 pub(crate) fn build_task(state_dynamic: &mut StateDynamic, task_id: TaskId, spec: Task) {
+    for (upstream_id, upstream_type) in get_spec_upstream(&spec) {
+        get_task(state_dynamic, upstream_id).downstream.borrow_mut().insert(task_id.clone(), upstream_type.clone());
+    }
     let specific;
     match spec {
         interface::task::Task::Empty(spec) => {
-            for (upstream_id, upstream_type) in &spec.upstream {
-                get_task(state_dynamic, upstream_id)
-                    .downstream
-                    .borrow_mut()
-                    .insert(task_id.clone(), upstream_type.clone());
-            }
+            schedule_task(state_dynamic, &task_id, &spec.schedule, true);
             specific = TaskStateSpecific::Empty(TaskStateEmpty {
                 started: Cell::new((false, Utc::now())),
                 spec: spec,
             });
         },
         interface::task::Task::Long(spec) => {
-            for (upstream_id, upstream_type) in &spec.upstream {
-                get_task(state_dynamic, &upstream_id)
-                    .downstream
-                    .borrow_mut()
-                    .insert(task_id.clone(), upstream_type.clone());
-            }
+            schedule_task(state_dynamic, &task_id, &spec.schedule, true);
             specific = TaskStateSpecific::Long(TaskStateLong {
                 spec: spec,
                 state: Cell::new((ProcState::Stopped, Utc::now())),
@@ -121,32 +541,21 @@ pub(crate) fn build_task(state_dynamic: &mut StateDynamic, task_id: TaskId, spec
             });
         },
         interface::task::Task::Short(spec) => {
-            for rule in &spec.schedule {
-                state_dynamic
-                    .schedule
-                    .entry(calc_next_instant(Utc::now(), Instant::now(), rule, true))
-                    .or_default()
-                    .push(ScheduleRule::new((task_id.clone(), rule.clone())));
-            }
-            state_dynamic.notify_reschedule.notify_one();
-            for (upstream_id, upstream_type) in &spec.upstream {
-                get_task(state_dynamic, &upstream_id)
-                    .downstream
-                    .borrow_mut()
-                    .insert(task_id.clone(), upstream_type.clone());
-            }
+            schedule_task(state_dynamic, &task_id, &spec.schedule, true);
             specific = TaskStateSpecific::Short(TaskStateShort {
                 spec: spec,
                 state: Cell::new((ProcState::Stopped, Utc::now())),
                 stop: RefCell::new(None),
                 pid: Cell::new(None),
                 failed_start_count: Cell::new(0),
+                triggered: Cell::new(false),
             });
         },
     }
     let task = state_dynamic.task_alloc.insert(TaskState_ {
         id: task_id.clone(),
         direct_on: Cell::new((false, Utc::now())),
+        direct_on_reason: RefCell::new(None),
         transitive_on: Cell::new((false, Utc::now())),
         downstream: Default::default(),
         specific: specific,
@@ -171,18 +580,156 @@ pub(crate) fn delete_task(state_dynamic: &mut StateDynamic, task_id: &TaskId) {
     });
 
     // Remove schedulings
-    let mut modified = false;
-    state_dynamic.schedule.retain(|_, v| {
-        v.retain(|r| {
-            let keep = r.0 != *task_id;
-            if !keep {
-                modified = true;
-            }
-            return keep;
-        });
-        return !v.is_empty();
+    unschedule_task(state_dynamic, task_id);
+}
+
+/// A deleted task with everything needed to put it back.
+pub(crate) struct RemovedTask {
+    pub(crate) id: TaskId,
+    pub(crate) spec: Task,
+    pub(crate) downstream: HashMap<TaskId, DependencyType>,
+    pub(crate) direct_on: (bool, DateTime<Utc>),
+    pub(crate) direct_on_reason: Option<ControlReason>,
+    pub(crate) transitive_on: (bool, DateTime<Utc>),
+}
+
+/// Delete a task, keeping its spec and control state. The task should be stopped.
+pub(crate) fn remove_task(state_dynamic: &mut StateDynamic, task_id: &TaskId) -> RemovedTask {
+    let task = get_task(state_dynamic, task_id);
+    let out = RemovedTask {
+        id: task_id.clone(),
+        spec: get_task_spec(task),
+        downstream: task.downstream.borrow().clone(),
+        direct_on: task.direct_on.get(),
+        direct_on_reason: task.direct_on_reason.borrow().clone(),
+        transitive_on: task.transitive_on.get(),
+    };
+    delete_task(state_dynamic, task_id);
+    return out;
+}
+
+/// Undo `remove_task`. Upstream tasks must already exist.
+pub(crate) fn restore_task(state_dynamic: &mut StateDynamic, removed: RemovedTask) {
+    build_task(state_dynamic, removed.id.clone(), removed.spec);
+    let task = get_task(state_dynamic, &removed.id);
+    task.downstream.borrow_mut().extend(removed.downstream);
+    task.direct_on.set(removed.direct_on);
+    *task.direct_on_reason.borrow_mut() = removed.direct_on_reason;
+    task.transitive_on.set(removed.transitive_on);
+}
+
+/// Swap a stopped task's spec, keeping its control state, downstream edges and
+/// waiters.
+pub(crate) fn replace_task(state_dynamic: &mut StateDynamic, task_id: &TaskId, spec: Task) {
+    let task = get_task(state_dynamic, task_id);
+    let started_waiters = task.started_waiters.take();
+    let stopped_waiters = task.stopped_waiters.take();
+    let removed = remove_task(state_dynamic, task_id);
+    restore_task(state_dynamic, RemovedTask {
+        spec: spec,
+        ..removed
     });
-    if modified {
-        state_dynamic.notify_reschedule.notify_one();
+    let task = get_task(state_dynamic, task_id);
+    *task.started_waiters.borrow_mut() = started_waiters;
+    *task.stopped_waiters.borrow_mut() = stopped_waiters;
+}
+
+fn instantiate_task_inner(
+    state: &State,
+    state_dynamic: &mut StateDynamic,
+    errors: &mut Vec<loga::Error>,
+    stack: &mut Vec<TaskId>,
+    built: &mut Vec<TaskId>,
+    task_id: &TaskId,
+) {
+    if state_dynamic.tasks.contains_key(task_id) {
+        return;
+    }
+    let Some((template_id, instance)) = split_instance_id(task_id) else {
+        errors.push(loga::err(format!("Unknown task [{}]", task_id)));
+        return;
+    };
+    let Some(template) = state_dynamic.templates.get(&template_id) else {
+        errors.push(loga::err(format!("Unknown template [{}] for instance [{}]", template_id, task_id)));
+        return;
+    };
+    if stack.contains(task_id) {
+        errors.push(
+            loga::err(format!("Template instance [{}] depends on itself: {}", task_id, stack.join(" -> "))),
+        );
+        return;
+    }
+    let spec = instantiate_template(template, instance);
+
+    // Create any upstream instances first
+    let upstream: Vec<TaskId> = get_spec_upstream(&spec).into_iter().map(|(id, _)| id.clone()).collect();
+    stack.push(task_id.clone());
+    for upstream_id in upstream {
+        if split_instance_id(&upstream_id).is_none() {
+            // Regular tasks, missing ones will be reported during validation
+            continue;
+        }
+        instantiate_task_inner(state, state_dynamic, errors, stack, built, &upstream_id);
+    }
+    stack.pop();
+    if !errors.is_empty() {
+        return;
+    }
+    validate_new_task(state, state_dynamic, errors, task_id, &spec);
+    if !errors.is_empty() {
+        return;
+    }
+    build_task(state_dynamic, task_id.clone(), spec);
+    built.push(task_id.clone());
+}
+
+/// Create a task from its template (ex: `worker@eu1` from `worker@`), along with
+/// any upstream instances that don't exist yet. Instances created this way are
+/// deleted again once they're off and no longer needed. Returns the created
+/// instances, upstream first.
+pub(crate) fn instantiate_task(
+    state: &State,
+    state_dynamic: &mut StateDynamic,
+    task_id: &TaskId,
+) -> Result<Vec<TaskId>, loga::Error> {
+    let mut errors = vec![];
+    let mut built = vec![];
+    instantiate_task_inner(state, state_dynamic, &mut errors, &mut vec![], &mut built, task_id);
+    if !errors.is_empty() {
+        for task_id in built.iter().rev() {
+            delete_task(state_dynamic, task_id);
+        }
+        return Err(loga::agg_err("Error creating task from template", errors));
+    }
+    state_dynamic.instances.extend(built.iter().cloned());
+    return Ok(built);
+}
+
+/// Delete on-demand template instances that are off and stopped, have nothing
+/// depending on them, and aren't scheduled.
+pub(crate) fn delete_unused_instances(state_dynamic: &mut StateDynamic) {
+    loop {
+        let mut unused = vec![];
+        for task_id in &state_dynamic.instances {
+            let Some(task) = maybe_get_task(state_dynamic, task_id) else {
+                // Deleted some other way (ex: started action `delete`)
+                unused.push(task_id.clone());
+                continue;
+            };
+            if is_task_on(task) || !is_task_stopped(task) || !task.downstream.borrow().is_empty() ||
+                !get_task_schedule(task).0.is_empty() {
+                continue;
+            }
+            unused.push(task_id.clone());
+        }
+        if unused.is_empty() {
+            break;
+        }
+        for task_id in unused {
+            state_dynamic.instances.remove(&task_id);
+            if state_dynamic.tasks.contains_key(&task_id) {
+                delete_task(state_dynamic, &task_id);
+            }
+        }
     }
 }
@@ -0,0 +1,79 @@
+#![cfg(test)]
+
+use {
+    super::{
+        task_create_delete::validate_task_graph,
+        task_plan_test::{
+            build_state,
+            spec,
+            task_empty,
+        },
+    },
+    puteron::interface::{
+        base::TaskId,
+        task::{
+            DependencyType,
+            Task,
+        },
+    },
+    std::collections::BTreeMap,
+};
+
+fn specs(specs: impl IntoIterator<Item = (&'static str, Task)>) -> BTreeMap<TaskId, Task> {
+    return specs.into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+}
+
+#[test]
+fn graph_ok() {
+    let state_dynamic = build_state([task_empty("a", false, [])]);
+    let mut errors = vec![];
+    validate_task_graph(
+        &state_dynamic,
+        &specs([("b", spec([("a", DependencyType::Strong)])), ("c", spec([("b", DependencyType::Weak)]))]),
+        &mut errors,
+    );
+    assert!(errors.is_empty(), "{:?}", errors);
+}
+
+#[test]
+fn graph_direct_cycle() {
+    let state_dynamic = build_state([]);
+    let mut errors = vec![];
+    validate_task_graph(
+        &state_dynamic,
+        &specs([("a", spec([("b", DependencyType::Strong)])), ("b", spec([("a", DependencyType::After)]))]),
+        &mut errors,
+    );
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].to_string().contains("dependency cycle"), "{}", errors[0]);
+}
+
+#[test]
+fn graph_cycle_replacing_existing() {
+    let state_dynamic = build_state([task_empty("a", false, []), task_empty("b", false, [("a", DependencyType::Strong)])]);
+    let mut errors = vec![];
+    validate_task_graph(&state_dynamic, &specs([("a", spec([("b", DependencyType::Weak)]))]), &mut errors);
+    assert_eq!(errors.len(), 1);
+}
+
+#[test]
+fn graph_cycle_via_template_instance() {
+    let mut state_dynamic = build_state([]);
+    state_dynamic.templates.insert("w@".to_string(), spec([("a", DependencyType::Strong)]));
+    let mut errors = vec![];
+    validate_task_graph(&state_dynamic, &specs([("a", spec([("w@x", DependencyType::Strong)]))]), &mut errors);
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].to_string().contains("a -> w@x -> a"), "{}", errors[0]);
+}
+
+#[test]
+fn graph_conflicts_not_cycle() {
+    let state_dynamic = build_state([]);
+    let mut errors = vec![];
+    validate_task_graph(
+        &state_dynamic,
+        &specs([("a", spec([("b", DependencyType::Strong)])), ("b", spec([("a", DependencyType::Conflicts)]))]),
+        &mut errors,
+    );
+    assert!(errors.is_empty(), "{:?}", errors);
+}
@@ -9,16 +9,37 @@ use {
             plan_event_started,
             plan_event_stopped,
             plan_event_stopping,
+            plan_restart_task,
             plan_set_task_direct_off,
             plan_set_task_direct_on,
+            plan_start_if_on,
+            plan_stop_released_upstream,
+            propagate_transitive_off,
             ExecutePlan,
         },
         task_util::{
+            control_reason,
+            get_managed_directories,
             get_short_task_started_action,
+            is_task_on,
+            is_task_stopped,
+            managed_directory_path,
+            maybe_get_task,
+            substitute_task_command,
+            substitute_task_path,
+            substitute_task_string,
+            validate_managed_directory,
+            walk_task_upstream,
         },
     },
     crate::demon::{
-        task_create_delete::delete_task,
+        task_create_delete::{
+            delete_task,
+            delete_unused_instances,
+            replace_task,
+            validate_new_task,
+            validate_task_graph,
+        },
         task_util::get_task,
     },
     chrono::Utc,
@@ -37,7 +58,18 @@ use {
         interface::{
             self,
             base::TaskId,
-            ipc::ProcState,
+            ipc::{
+                AuditEntry,
+                ControlReason,
+                ControlSource,
+                IpcError,
+                ProcState,
+            },
+            task::{
+                ReloadSignal,
+                StartedCheck,
+                Task,
+            },
         },
         time::{
             SimpleDuration,
@@ -49,8 +81,18 @@ use {
         termios::Pid,
     },
     std::{
-        collections::HashSet,
+        collections::{
+            BTreeMap,
+            HashSet,
+        },
+        ffi::OsString,
+        fs::Permissions,
         future::Future,
+        os::unix::fs::PermissionsExt,
+        path::{
+            Path,
+            PathBuf,
+        },
         pin::Pin,
         process::Stdio,
         sync::Arc,
@@ -60,6 +102,7 @@ use {
     tokio::{
         io::{
             AsyncBufReadExt,
+            AsyncWriteExt,
             BufReader,
         },
         net::TcpStream,
@@ -73,6 +116,7 @@ use {
         time::{
             sleep,
             timeout,
+            Instant,
         },
     },
     tokio_stream::{
@@ -83,18 +127,22 @@ use {
 
 fn log_starting(state: &State, task_id: &TaskId) {
     state.log.log_with(loga::DEBUG, "State change: starting (0)", ea!(task = task_id));
+    state.notify_state.notify_waiters();
 }
 
 fn log_started(state: &State, task_id: &TaskId) {
     state.log.log_with(loga::DEBUG, "State change: started (1)", ea!(task = task_id));
+    state.notify_state.notify_waiters();
 }
 
 fn log_stopping(state: &State, task_id: &TaskId) {
     state.log.log_with(loga::DEBUG, "State change: stopping (2)", ea!(task = task_id));
+    state.notify_state.notify_waiters();
 }
 
 fn log_stopped(state: &State, task_id: &TaskId) {
     state.log.log_with(loga::DEBUG, "State change: stopped (3)", ea!(task = task_id));
+    state.notify_state.notify_waiters();
 }
 
 type LoggerRetFuture =
@@ -109,34 +157,262 @@ type LoggerRetFuture =
         >,
     >;
 
-fn spawn_proc(
+fn parse_environment_value(v: &str) -> Result<String, loga::Error> {
+    if v.len() >= 2 && v.starts_with('\'') && v.ends_with('\'') {
+        return Ok(v[1..v.len() - 1].to_string());
+    }
+    if v.len() >= 2 && v.starts_with('"') && v.ends_with('"') {
+        let mut out = String::new();
+        let mut chars = v[1..v.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('\\') => out.push('\\'),
+                Some('"') => out.push('"'),
+                Some(c) => {
+                    return Err(loga::err(format!("Unknown escape `\\{}` in quoted value", c)));
+                },
+                None => {
+                    return Err(loga::err("Quoted value ends with a lone `\\`"));
+                },
+            }
+        }
+        return Ok(out);
+    }
+    return Ok(v.to_string());
+}
+
+async fn read_environment_file(f: &interface::task::EnvironmentFile) -> Result<Vec<(String, String)>, loga::Error> {
+    let text = match tokio::fs::read_to_string(&f.path).await {
+        Ok(t) => t,
+        Err(e) => {
+            if f.optional && e.kind() == std::io::ErrorKind::NotFound {
+                return Ok(vec![]);
+            }
+            return Err(e.context_with("Error reading environment file", ea!(path = f.path.dbg_str())));
+        },
+    };
+    let mut out = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((k, v)) = line.split_once('=') else {
+            return Err(
+                loga::err_with(
+                    "Environment file line is missing `=`",
+                    ea!(path = f.path.dbg_str(), line = i + 1),
+                ),
+            );
+        };
+        let v =
+            parse_environment_value(
+                v.trim(),
+            ).context_with("Invalid environment file value", ea!(path = f.path.dbg_str(), line = i + 1))?;
+        out.push((k.trim().to_string(), v));
+    }
+    return Ok(out);
+}
+
+fn credentials_dir(state: &State, task_id: &TaskId) -> PathBuf {
+    return state.runtime_dir.join("puteron").join("credentials").join(task_id);
+}
+
+/// Copy the command's credentials into a fresh private directory, returning the
+/// directory. Tasks run as the demon's user so the copies are owned by it. If
+/// copying fails the directory is removed again.
+async fn prepare_credentials(
     state: &State,
     task_id: &TaskId,
     spec: &interface::task::Command,
-) -> Result<(Child, Pid, LoggerRetFuture), loga::Error> {
-    // Prep command and args
-    let mut command = Command::new(&spec.line[0]);
-    command.args(&spec.line[1..]);
+) -> Result<Option<PathBuf>, loga::Error> {
+    if spec.credentials.is_empty() {
+        return Ok(None);
+    }
+    let dir = credentials_dir(state, task_id);
+    let res = async {
+        ta_return!((), loga::Error);
+        match tokio::fs::remove_dir_all(&dir).await {
+            Ok(_) => { },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => { },
+            Err(e) => {
+                return Err(e.context_with("Error removing old credentials directory", ea!(path = dir.dbg_str())));
+            },
+        }
+        tokio::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .await
+            .context_with("Error creating credentials directory", ea!(path = dir.dbg_str()))?;
+        for (name, source) in &spec.credentials {
+            let data =
+                tokio::fs::read(source)
+                    .await
+                    .context_with("Error reading credential source", ea!(credential = name, path = source.dbg_str()))?;
+            let dest = dir.join(name);
+            let mut f =
+                tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o400)
+                    .open(&dest)
+                    .await
+                    .context_with("Error creating credential", ea!(credential = name, path = dest.dbg_str()))?;
+            f
+                .write_all(&data)
+                .await
+                .context_with("Error writing credential", ea!(credential = name, path = dest.dbg_str()))?;
+        }
+        return Ok(());
+    }.await;
+    if let Err(e) = res {
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                state
+                    .log
+                    .log_err(
+                        loga::WARN,
+                        e.context_with("Error removing partial credentials directory", ea!(path = dir.dbg_str())),
+                    );
+            }
+        }
+        return Err(e);
+    }
+    return Ok(Some(dir));
+}
 
-    // Working dir
-    match &spec.working_directory {
-        Some(w) => {
-            command.current_dir(w);
-        },
-        None => {
-            command.current_dir("/");
+fn remove_credentials(state: &State, task_id: &TaskId) {
+    let dir = credentials_dir(state, task_id);
+    match std::fs::remove_dir_all(&dir) {
+        Ok(_) => { },
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                state
+                    .log
+                    .log_err(
+                        loga::WARN,
+                        e.context_with("Error removing credentials directory", ea!(path = dir.dbg_str())),
+                    );
+            }
         },
     }
+}
 
-    // Env vars
-    command.env_clear();
-    for (k, v) in &state.env {
-        if !spec.environment.clean || spec.environment.keep.get(k).cloned().unwrap_or(false) {
-            command.env(k, v);
+/// Create a managed directory and any missing parents below `base`, setting `mode`
+/// on each directory created (and on the directory itself if it already existed).
+/// Tasks run as the demon's user so the directories are owned by it.
+async fn create_managed_directory(base: &Path, path: &Path, mode: u32) -> Result<(), loga::Error> {
+    tokio::fs::create_dir_all(base).await.context_with("Error creating base directory", ea!(path = base.dbg_str()))?;
+    let mut at = base.to_path_buf();
+    for component in path.strip_prefix(base).unwrap().components() {
+        at.push(component);
+        match tokio::fs::create_dir(&at).await {
+            Ok(_) => { },
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                if at != path {
+                    continue;
+                }
+            },
+            Err(e) => {
+                return Err(e.context_with("Error creating managed directory", ea!(path = at.dbg_str())));
+            },
+        }
+        tokio::fs::set_permissions(
+            &at,
+            Permissions::from_mode(mode),
+        )
+            .await
+            .context_with("Error setting managed directory permissions", ea!(path = at.dbg_str()))?;
+    }
+    return Ok(());
+}
+
+/// Remove managed directories marked `remove_on_stop`.
+fn remove_managed_directories(state: &State, task_id: &TaskId, spec: &interface::task::Command) {
+    for (_, base, dir) in get_managed_directories(state, spec) {
+        if !dir.remove_on_stop {
+            continue;
+        }
+        let path = managed_directory_path(base, task_id, dir);
+        match std::fs::remove_dir_all(&path) {
+            Ok(_) => { },
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    state
+                        .log
+                        .log_err(
+                            loga::WARN,
+                            e.context_with("Error removing managed directory", ea!(path = path.dbg_str())),
+                        );
+                }
+            },
+        }
+    }
+}
+
+/// Build the environment for a (substituted) command: the demon env filtered by
+/// `clean`/`keep`, added values, environment files, then the credentials and
+/// managed directory paths. Environment files are read every time so changes are
+/// picked up.
+async fn build_command_env(
+    state: &State,
+    task_id: &TaskId,
+    spec: &interface::task::Command,
+) -> Result<Vec<(String, OsString)>, loga::Error> {
+    let mut demon_env = state.env.clone();
+    for f in &state.env_files {
+        demon_env.extend(read_environment_file(f).await?);
+    }
+    let mut out = vec![];
+    for (k, v) in demon_env {
+        if !spec.environment.clean || spec.environment.keep.get(&k).cloned().unwrap_or(false) {
+            out.push((k, OsString::from(v)));
         }
     }
     for (k, v) in &spec.environment.add {
-        command.env(k, v);
+        out.push((k.clone(), OsString::from(v)));
+    }
+    for f in &spec.environment.files {
+        for (k, v) in read_environment_file(f).await? {
+            out.push((k, OsString::from(v)));
+        }
+    }
+    if !spec.credentials.is_empty() {
+        out.push(("CREDENTIALS_DIRECTORY".to_string(), credentials_dir(state, task_id).into_os_string()));
+    }
+    for (env_name, base, dir) in get_managed_directories(state, spec) {
+        out.push((env_name.to_string(), managed_directory_path(base, task_id, dir).into_os_string()));
+    }
+    return Ok(out);
+}
+
+async fn spawn_proc(
+    state: &State,
+    task_id: &TaskId,
+    spec: &interface::task::Command,
+) -> Result<(Child, Pid, LoggerRetFuture), loga::Error> {
+    // Expand variables
+    let spec = substitute_task_command(state, task_id, spec)?;
+
+    // Prep command and args
+    let mut command = Command::new(&spec.line[0]);
+    command.args(&spec.line[1..]);
+    command.current_dir(spec.working_directory.as_deref().unwrap_or(Path::new("/")));
+    command.env_clear();
+    command.envs(build_command_env(state, task_id, &spec).await?);
+
+    // Prepare what the env points to
+    prepare_credentials(state, task_id, &spec).await?;
+    for (_, base, dir) in get_managed_directories(state, &spec) {
+        let mode = validate_managed_directory(dir)?;
+        create_managed_directory(base, &managed_directory_path(base, task_id, dir), mode).await?;
     }
     let log = state.log.fork(ea!(command = command.dbg_str()));
     log.log_with(loga::DEBUG, "Spawning task process", ea!(task = task_id));
@@ -148,7 +424,13 @@ fn spawn_proc(
 
     // Launch
     command.process_group(0);
-    let mut child = command.spawn().context("Failed to spawn subprocess")?;
+    let mut child = match command.spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            remove_credentials(state, task_id);
+            return Err(e.context("Failed to spawn subprocess"));
+        },
+    };
     drop(command);
     let pid = Pid::from_raw(child.id().unwrap() as i32).unwrap();
 
@@ -184,6 +466,41 @@ fn spawn_proc(
     return Ok((child, pid, logger));
 }
 
+/// Expand variables in a started check. Errors are caught when the task is loaded,
+/// but if it fails anyway the check is used as is.
+fn substitute_started_check(state: &State, log: &Log, task_id: &TaskId, check: &StartedCheck) -> StartedCheck {
+    match check {
+        StartedCheck::TcpSocket(_) => {
+            return check.clone();
+        },
+        StartedCheck::Path(p) => {
+            match substitute_task_path(state, task_id, p) {
+                Ok(p) => {
+                    return StartedCheck::Path(p);
+                },
+                Err(e) => {
+                    log.log_err(
+                        loga::WARN,
+                        e.context("Error expanding variables in started check path, using it as is"),
+                    );
+                    return check.clone();
+                },
+            }
+        },
+    }
+}
+
+async fn is_started_check_passing(check: &StartedCheck) -> bool {
+    match check {
+        StartedCheck::TcpSocket(addr) => {
+            return matches!(timeout(Duration::from_secs(1), TcpStream::connect(addr)).await, Ok(Ok(_)));
+        },
+        StartedCheck::Path(p) => {
+            return tokio::fs::try_exists(p).await.unwrap_or(false);
+        },
+    }
+}
+
 async fn gentle_stop_proc(
     log: &Log,
     pid: Pid,
@@ -259,40 +576,320 @@ fn event_started(state: &Arc<State>, state_dynamic: &mut StateDynamic, task_id:
 }
 
 /// After state change
+/// A task that's still on after stopping (ex: restarted, or stopped because an
+/// upstream task stopped) is started again once all its upstream tasks are
+/// started. Waiters for it to start are only canceled if it's off.
 fn event_stopped(state: &Arc<State>, state_dynamic: &mut StateDynamic, task_id: &TaskId) {
     log_stopped(state, task_id);
+    let replacing = state_dynamic.replacements.contains_key(task_id);
     let mut plan = ExecutePlan::default();
     plan_event_stopped(state_dynamic, &mut plan, task_id);
+    if !replacing {
+        // Start again if still on (ex: restarting, or an upstream restarted before this
+        // finished stopping)
+        plan_start_if_on(state_dynamic, &mut plan, task_id);
+    }
     execute(state, state_dynamic, plan);
     let task = get_task(state_dynamic, task_id);
     for waiter in task.stopped_waiters.borrow_mut().split_off(0) {
         _ = waiter.send(true);
     }
-    for waiter in task.started_waiters.borrow_mut().split_off(0) {
-        _ = waiter.send(false);
+    if replacing {
+        apply_replacement(state, state_dynamic, task_id);
+    }
+    let task = get_task(state_dynamic, task_id);
+    if !is_task_on(task) {
+        for waiter in task.started_waiters.borrow_mut().split_off(0) {
+            _ = waiter.send(false);
+        }
     }
 }
 
-pub(crate) fn set_task_user_on(state: &Arc<State>, state_dynamic: &mut StateDynamic, root_task_id: &TaskId) {
+/// Swap in the pending replacement spec of a stopped task. If the task is on, the
+/// new spec's upstream is turned on and the task is started again. Upstream tasks
+/// only the old spec needed are stopped. If the new spec is no longer valid (ex:
+/// an upstream was deleted while the task was stopping) the old spec is kept.
+fn apply_replacement(state: &Arc<State>, state_dynamic: &mut StateDynamic, task_id: &TaskId) {
+    let Some(spec) = state_dynamic.replacements.remove(task_id) else {
+        return;
+    };
+    let mut errors = vec![];
+    validate_new_task(state, state_dynamic, &mut errors, task_id, &spec);
+    validate_task_graph(state_dynamic, &BTreeMap::from([(task_id.clone(), spec.clone())]), &mut errors);
+    if !errors.is_empty() {
+        state
+            .log
+            .log_err(
+                loga::WARN,
+                loga::agg_err_with(
+                    "Replacement task spec is no longer valid, keeping the current spec",
+                    errors,
+                    ea!(task = task_id),
+                ),
+            );
+        let mut plan = ExecutePlan::default();
+        plan_start_if_on(state_dynamic, &mut plan, task_id);
+        execute(state, state_dynamic, plan);
+        return;
+    }
+
+    // Release the old upstream
+    let task = get_task(state_dynamic, task_id);
+    let direct_on = task.direct_on.get();
+    let transitive_on = task.transitive_on.get();
+    let old_upstream = walk_task_upstream(task, |upstream| upstream.map(|(id, _)| id.clone()).collect::<Vec<_>>());
+    task.direct_on.set((false, Utc::now()));
+    task.transitive_on.set((false, Utc::now()));
+    propagate_transitive_off(state_dynamic, task_id);
+
+    // Swap, then turn the task back on with the new upstream
+    replace_task(state_dynamic, task_id, spec);
     let mut plan = ExecutePlan::default();
-    plan_set_task_direct_on(state_dynamic, &mut plan, root_task_id);
+    if direct_on.0 || transitive_on.0 {
+        plan_set_task_direct_on(state_dynamic, &mut plan, task_id);
+        let task = get_task(state_dynamic, task_id);
+        task.direct_on.set(direct_on);
+        task.transitive_on.set(transitive_on);
+    }
+    plan_stop_released_upstream(state_dynamic, &mut plan, old_upstream);
+    execute_user_plan(state, state_dynamic, plan);
+}
+
+/// Replace a task's spec, stopping it (and its downstream tasks) first if it's
+/// running and starting everything again afterwards. Unlike deleting and adding the
+/// task, control state and downstream edges are kept.
+pub(crate) fn replace_task_restart(
+    state: &Arc<State>,
+    state_dynamic: &mut StateDynamic,
+    task_id: &TaskId,
+    spec: Task,
+) {
+    state_dynamic.replacements.insert(task_id.clone(), spec);
+    let mut plan = ExecutePlan::default();
+    plan_restart_task(state_dynamic, &mut plan, task_id);
     execute(state, state_dynamic, plan);
+    if is_task_stopped(get_task(state_dynamic, task_id)) {
+        apply_replacement(state, state_dynamic, task_id);
+    }
 }
 
-pub(crate) fn set_task_user_off(state: &Arc<State>, state_dynamic: &mut StateDynamic, task_id: &TaskId) {
+/// Stop a task and start it again, leaving its control state as is. Downstream
+/// tasks are stopped and started again along with it.
+pub(crate) fn restart_task(state: &Arc<State>, state_dynamic: &mut StateDynamic, task_id: &TaskId) {
     let mut plan = ExecutePlan::default();
-    plan_set_task_direct_off(state_dynamic, &mut plan, task_id);
+    plan_restart_task(state_dynamic, &mut plan, task_id);
     execute(state, state_dynamic, plan);
 }
 
+/// Send a long task's reload signal or run its reload command (in the environment
+/// and working directory of the task's command). The task stays started
+/// throughout. If `wait` is set and the task has a started check, this returns
+/// once the check passes (immediately if the process keeps its listener or check
+/// file through the reload). The reload command and the wait are limited by the
+/// task's `reload_timeout`.
+pub(crate) async fn reload_task(state: &Arc<State>, task_id: &TaskId, wait: bool) -> Result<(), IpcError> {
+    let log = state.log.fork(ea!(task = task_id));
+    let (spec, pid) = {
+        let state_dynamic = state.dynamic.lock().unwrap();
+        let Some(task) = maybe_get_task(&state_dynamic, task_id) else {
+            return Err(IpcError::UnknownTask { task: task_id.clone() });
+        };
+        let TaskStateSpecific::Long(specific) = &task.specific else {
+            return Err(IpcError::NotReloadable { task: task_id.clone() });
+        };
+        if specific.spec.reload_signal.is_none() && specific.spec.reload_command.is_none() {
+            return Err(IpcError::NotReloadable { task: task_id.clone() });
+        }
+        let Some(pid) = specific.pid.get().filter(|_| specific.state.get().0 == ProcState::Started) else {
+            return Err(IpcError::TaskNotStarted { task: task_id.clone() });
+        };
+        (specific.spec.clone(), pid)
+    };
+    let reload_failed = |message: String| IpcError::ReloadFailed {
+        task: task_id.clone(),
+        message: message,
+    };
+    let started_check = match (wait, &spec.started_check) {
+        (true, Some(c)) => Some(substitute_started_check(state, &log, task_id, c)),
+        _ => None,
+    };
+    let reload_timeout = spec.reload_timeout.map(|x| x.into()).unwrap_or(Duration::from_secs(30));
+    log.log(loga::INFO, "Reloading");
+
+    // Signal
+    if let Some(signal) = spec.reload_signal {
+        let signal = match signal {
+            ReloadSignal::Hup => Signal::Hup,
+            ReloadSignal::Int => Signal::Int,
+            ReloadSignal::Quit => Signal::Quit,
+            ReloadSignal::Usr1 => Signal::Usr1,
+            ReloadSignal::Usr2 => Signal::Usr2,
+            ReloadSignal::Winch => Signal::Winch,
+        };
+        if let Err(e) = rustix::process::kill_process(Pid::from_raw(pid).unwrap(), signal) {
+            return Err(reload_failed(format!("Error sending signal to process: {}", e)));
+        }
+    }
+
+    // Command
+    if let Some(line) = &spec.reload_command {
+        let mut line2 = vec![];
+        for arg in line {
+            line2.push(
+                substitute_task_string(
+                    state,
+                    task_id,
+                    arg,
+                ).map_err(|e| reload_failed(format!("Error in reload command: {}", e)))?,
+            );
+        }
+        let context =
+            substitute_task_command(
+                state,
+                task_id,
+                &spec.command,
+            ).map_err(|e| reload_failed(format!("Error in task command: {}", e)))?;
+        let env =
+            build_command_env(
+                state,
+                task_id,
+                &context,
+            ).await.map_err(|e| reload_failed(format!("Error building reload command environment: {}", e)))?;
+        let mut command = Command::new(&line2[0]);
+        command.args(&line2[1..]);
+        command.current_dir(context.working_directory.as_deref().unwrap_or(Path::new("/")));
+        command.env_clear();
+        command.envs(env);
+        command.env("MAINPID", pid.to_string());
+        command.stdin(Stdio::null());
+        command.kill_on_drop(true);
+        let output = match timeout(reload_timeout, command.output()).await {
+            Ok(r) => r.map_err(|e| reload_failed(format!("Error running reload command: {}", e)))?,
+            Err(_) => {
+                return Err(reload_failed(format!("Reload command didn't finish within {:?}", reload_timeout)));
+            },
+        };
+        if !output.status.success() {
+            return Err(
+                reload_failed(
+                    format!(
+                        "Reload command exited with {}: {}",
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ),
+                ),
+            );
+        }
+    }
+
+    // Wait for started check
+    if let Some(c) = started_check {
+        let deadline = Instant::now() + reload_timeout;
+        while !is_started_check_passing(&c).await {
+            {
+                let state_dynamic = state.dynamic.lock().unwrap();
+                let same_process =
+                    maybe_get_task(&state_dynamic, task_id)
+                        .and_then(|t| exenum!(&t.specific, TaskStateSpecific:: Long(s) => s))
+                        .is_some_and(|s| s.pid.get() == Some(pid));
+                if !same_process {
+                    return Err(reload_failed("Process exited before the started check passed".to_string()));
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(reload_failed(format!("Started check didn't pass within {:?}", reload_timeout)));
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+    return Ok(());
+}
+
+const AUDIT_LOG_SIZE: usize = 1000;
+
+fn record_control(state_dynamic: &mut StateDynamic, task_id: &TaskId, on: bool, reason: ControlReason) {
+    *get_task(state_dynamic, task_id).direct_on_reason.borrow_mut() = Some(reason.clone());
+    if state_dynamic.audit_log.len() >= AUDIT_LOG_SIZE {
+        state_dynamic.audit_log.pop_front();
+    }
+    state_dynamic.audit_log.push_back(AuditEntry {
+        task: task_id.clone(),
+        on: on,
+        reason: reason,
+    });
+}
+
+fn record_plan_turned_off(state_dynamic: &mut StateDynamic, plan: &ExecutePlan) {
+    for task_id in &plan.turned_off {
+        let reason = get_task(state_dynamic, task_id).direct_on_reason.borrow().clone().unwrap();
+        record_control(state_dynamic, task_id, false, reason);
+    }
+}
+
+/// Turn a task on as part of a larger plan, see `execute_user_plan`.
+pub(crate) fn plan_task_user_on(
+    state_dynamic: &mut StateDynamic,
+    plan: &mut ExecutePlan,
+    root_task_id: &TaskId,
+    reason: ControlReason,
+) {
+    if !get_task(state_dynamic, root_task_id).direct_on.get().0 {
+        record_control(state_dynamic, root_task_id, true, reason);
+    }
+    plan_set_task_direct_on(state_dynamic, plan, root_task_id);
+}
+
+/// Turn a task off as part of a larger plan, see `execute_user_plan`.
+pub(crate) fn plan_task_user_off(
+    state_dynamic: &mut StateDynamic,
+    plan: &mut ExecutePlan,
+    task_id: &TaskId,
+    reason: ControlReason,
+) {
+    if get_task(state_dynamic, task_id).direct_on.get().0 {
+        record_control(state_dynamic, task_id, false, reason);
+    }
+    plan_set_task_direct_off(state_dynamic, plan, task_id);
+}
+
+pub(crate) fn execute_user_plan(state: &Arc<State>, state_dynamic: &mut StateDynamic, plan: ExecutePlan) {
+    record_plan_turned_off(state_dynamic, &plan);
+    execute(state, state_dynamic, plan);
+}
+
+pub(crate) fn set_task_user_on(
+    state: &Arc<State>,
+    state_dynamic: &mut StateDynamic,
+    root_task_id: &TaskId,
+    reason: ControlReason,
+) {
+    let mut plan = ExecutePlan::default();
+    plan_task_user_on(state_dynamic, &mut plan, root_task_id, reason);
+    execute_user_plan(state, state_dynamic, plan);
+}
+
+pub(crate) fn set_task_user_off(
+    state: &Arc<State>,
+    state_dynamic: &mut StateDynamic,
+    task_id: &TaskId,
+    reason: ControlReason,
+) {
+    let mut plan = ExecutePlan::default();
+    plan_task_user_off(state_dynamic, &mut plan, task_id, reason);
+    execute_user_plan(state, state_dynamic, plan);
+}
+
 macro_rules! handle_short_stopped2{
     // Work around borrow rules preventing code reuse
     ($state: expr, $state_dynamic: expr, $task_id: expr, $specific: expr) => {
         $specific.state.set((ProcState::Stopped, Utc::now()));
         $specific.pid.set(None);
-        let started_action = get_short_task_started_action(&$specific.spec);
+        remove_managed_directories(&$state, &$task_id, &$specific.spec.command);
+        let started_action = get_short_task_started_action($specific);
+        $specific.triggered.set(false);
         event_stopped(&$state, $state_dynamic, &$task_id);
-        if started_action == interface:: task:: ShortTaskStartedAction:: Delete {
+        if started_action == interface:: task:: ShortTaskStartedAction:: Delete &&
+            !is_task_on(get_task($state_dynamic, &$task_id)) {
             delete_task($state_dynamic, &$task_id);
         }
     };
@@ -302,6 +899,7 @@ fn handle_short_stopped(state: &Arc<State>, task_id: &TaskId) {
     let mut state_dynamic = state.dynamic.lock().unwrap();
     let specific = exenum!(&get_task(&state_dynamic, &task_id).specific, TaskStateSpecific:: Short(s) => s).unwrap();
     handle_short_stopped2!(state, &mut state_dynamic, task_id, specific);
+    delete_unused_instances(&mut state_dynamic);
 }
 
 fn execute(state: &Arc<State>, state_dynamic: &mut StateDynamic, plan: ExecutePlan) {
@@ -349,7 +947,7 @@ fn execute(state: &Arc<State>, state_dynamic: &mut StateDynamic, plan: ExecutePl
 
                             let end_action: EndAction = async {
                                 // Execute
-                                let (mut child, pid, logger) = match spawn_proc(&state, &task_id, &spec.command) {
+                                let (mut child, pid, logger) = match spawn_proc(&state, &task_id, &spec.command).await {
                                     Ok(x) => x,
                                     Err(e) => {
                                         log.log_err(loga::WARN, e.context("Failed to launch process"));
@@ -381,28 +979,11 @@ fn execute(state: &Arc<State>, state_dynamic: &mut StateDynamic, plan: ExecutePl
                                 // Wait until started
                                 let live_work = async {
                                     // Started check
-                                    match &spec.started_check {
-                                        None => { },
-                                        Some(c) => match c {
-                                            interface::task::StartedCheck::TcpSocket(addr) => {
-                                                loop {
-                                                    if timeout(Duration::from_secs(1), TcpStream::connect(addr))
-                                                        .await
-                                                        .is_ok() {
-                                                        break;
-                                                    }
-                                                    sleep(Duration::from_secs(1)).await;
-                                                }
-                                            },
-                                            interface::task::StartedCheck::Path(c) => {
-                                                loop {
-                                                    if c.exists() {
-                                                        break;
-                                                    }
-                                                    sleep(Duration::from_secs(1)).await;
-                                                }
-                                            },
-                                        },
+                                    if let Some(c) = &spec.started_check {
+                                        let c = substitute_started_check(&state, &log, &task_id, c);
+                                        while !is_started_check_passing(&c).await {
+                                            sleep(Duration::from_secs(1)).await;
+                                        }
                                     }
                                     {
                                         let mut state_dynamic = state.dynamic.lock().unwrap();
@@ -456,6 +1037,7 @@ fn execute(state: &Arc<State>, state_dynamic: &mut StateDynamic, plan: ExecutePl
                                             // May or may not have started; mark as starting + do state updates
                                             let specific = exenum!(&get_task(&state_dynamic, &task_id).specific, TaskStateSpecific:: Long(s) => s).unwrap();
                                             specific.pid.set(None);
+                                            remove_credentials(&state, &task_id);
                                             if specific.state.get().0 != ProcState::Starting {
                                                 specific.state.set((ProcState::Starting, Utc::now()));
                                             }
@@ -509,7 +1091,10 @@ fn execute(state: &Arc<State>, state_dynamic: &mut StateDynamic, plan: ExecutePl
                                 ).unwrap();
                             specific.pid.set(None);
                             specific.state.set((ProcState::Stopped, Utc::now()));
+                            remove_credentials(&state, &task_id);
+                            remove_managed_directories(&state, &task_id, &specific.spec.command);
                             event_stopped(&state, &mut state_dynamic, &task_id);
+                            delete_unused_instances(&mut state_dynamic);
                         }
                     }
                 });
@@ -545,7 +1130,7 @@ fn execute(state: &Arc<State>, state_dynamic: &mut StateDynamic, plan: ExecutePl
                             }
 
                             let end_action: EndAction = async {
-                                let (mut child, pid, logger) = match spawn_proc(&state, &task_id, &spec.command) {
+                                let (mut child, pid, logger) = match spawn_proc(&state, &task_id, &spec.command).await {
                                     Ok(x) => x,
                                     Err(e) => {
                                         log.log_err(loga::WARN, e.context("Failed to launch process"));
@@ -585,6 +1170,7 @@ fn execute(state: &Arc<State>, state_dynamic: &mut StateDynamic, plan: ExecutePl
                                                 TaskStateSpecific:: Short(s) => s
                                             ).unwrap();
                                         specific.pid.set(None);
+                                        remove_credentials(&state, &task_id);
                                         match r {
                                             Ok(r) => {
                                                 if r.code().filter(|c| success_codes.contains(c)).is_some() {
@@ -594,7 +1180,8 @@ fn execute(state: &Arc<State>, state_dynamic: &mut StateDynamic, plan: ExecutePl
                                                         specific.failed_start_count.set(0);
                                                         specific.state.set((ProcState::Started, Utc::now()));
                                                         let started_action =
-                                                            get_short_task_started_action(&specific.spec);
+                                                            get_short_task_started_action(specific);
+                                                        specific.triggered.set(false);
                                                         event_started(&state, &mut state_dynamic, &task_id);
                                                         match started_action {
                                                             interface::task::ShortTaskStartedAction::None => { },
@@ -604,7 +1191,9 @@ fn execute(state: &Arc<State>, state_dynamic: &mut StateDynamic, plan: ExecutePl
                                                                     &state,
                                                                     &mut state_dynamic,
                                                                     &task_id,
+                                                                    control_reason(ControlSource::StartedAction),
                                                                 );
+                                                                delete_unused_instances(&mut state_dynamic);
                                                             },
                                                         }
                                                     }
@@ -685,11 +1274,12 @@ fn execute(state: &Arc<State>, state_dynamic: &mut StateDynamic, plan: ExecutePl
                                             let specific =
                                                 exenum!(
                                                     &get_task(&state_dynamic, &task_id).specific,
-                                                    TaskStateSpecific:: Long(s) => s
+                                                    TaskStateSpecific:: Short(s) => s
                                                 ).unwrap();
                                             specific.state.set((ProcState::Stopping, Utc::now()));
                                         }
                                         gentle_stop_proc(&log, pid, child, logger, spec.stop_timeout).await;
+                                        remove_credentials(&state, &task_id);
 
                                         // Stopped
                                         handle_short_stopped(&state, &task_id);
@@ -0,0 +1,111 @@
+#![cfg(test)]
+
+use {
+    super::{
+        state::{
+            State,
+            TaskStateSpecific,
+        },
+        task_execute::replace_task_restart,
+        task_plan_test::{
+            build_state,
+            build_test_state,
+            spec,
+            task_empty,
+        },
+        task_util::{
+            get_task,
+            get_task_spec,
+            is_task_started,
+        },
+    },
+    chrono::Utc,
+    puteron::interface::task::{
+        DependencyType,
+        Task,
+    },
+    std::sync::Arc,
+};
+
+fn set_started(state: &Arc<State>, task_id: &str) {
+    let state_dynamic = state.dynamic.lock().unwrap();
+    let TaskStateSpecific::Empty(s) = &get_task(&state_dynamic, &task_id.to_string()).specific else {
+        unreachable!();
+    };
+    s.started.set((true, Utc::now()));
+}
+
+#[test]
+fn replace_moves_upstream() {
+    let state = build_test_state(build_state([
+        //. .
+        task_empty("a", false, []),
+        task_empty("b", true, [("a", DependencyType::Strong)]),
+        task_empty("c", false, []),
+    ]));
+    set_started(&state, "a");
+    set_started(&state, "b");
+    let mut state_dynamic = state.dynamic.lock().unwrap();
+    replace_task_restart(&state, &mut state_dynamic, &"b".to_string(), spec([("c", DependencyType::Strong)]));
+    assert!(state_dynamic.replacements.is_empty());
+    let a = get_task(&state_dynamic, &"a".to_string());
+    let b = get_task(&state_dynamic, &"b".to_string());
+    let c = get_task(&state_dynamic, &"c".to_string());
+    assert!(b.direct_on.get().0);
+    assert!(is_task_started(b));
+    assert!(c.transitive_on.get().0);
+    assert!(is_task_started(c));
+    assert!(!a.transitive_on.get().0);
+    assert!(!is_task_started(a));
+    assert!(a.downstream.borrow().is_empty());
+    assert_eq!(c.downstream.borrow().get("b"), Some(&DependencyType::Strong));
+}
+
+#[test]
+fn replace_invalid_keeps_spec() {
+    let state = build_test_state(build_state([
+        //. .
+        task_empty("a", false, []),
+        task_empty("b", true, [("a", DependencyType::Strong)]),
+    ]));
+    set_started(&state, "a");
+    set_started(&state, "b");
+    let mut state_dynamic = state.dynamic.lock().unwrap();
+    replace_task_restart(&state, &mut state_dynamic, &"b".to_string(), spec([("gone", DependencyType::Strong)]));
+    assert!(state_dynamic.replacements.is_empty());
+    let a = get_task(&state_dynamic, &"a".to_string());
+    let b = get_task(&state_dynamic, &"b".to_string());
+    let Task::Empty(s) = get_task_spec(b) else {
+        unreachable!();
+    };
+    assert_eq!(s.upstream.get("a"), Some(&DependencyType::Strong));
+    assert!(!s.upstream.contains_key("gone"));
+    assert!(is_task_started(b));
+    assert!(is_task_started(a));
+    assert!(a.transitive_on.get().0);
+}
+
+#[test]
+fn replace_keeps_own_conflicts() {
+    // The old `b` edges (conflicting with `a`) are still there when the replacement
+    // is validated
+    let state = build_test_state(build_state([
+        //. .
+        task_empty("a", false, []),
+        task_empty("b", true, [("a", DependencyType::Conflicts)]),
+        task_empty("c", false, []),
+    ]));
+    set_started(&state, "b");
+    let mut state_dynamic = state.dynamic.lock().unwrap();
+    replace_task_restart(
+        &state,
+        &mut state_dynamic,
+        &"b".to_string(),
+        spec([("a", DependencyType::Conflicts), ("c", DependencyType::Strong)]),
+    );
+    let Task::Empty(s) = get_task_spec(get_task(&state_dynamic, &"b".to_string())) else {
+        unreachable!();
+    };
+    assert_eq!(s.upstream.get("c"), Some(&DependencyType::Strong));
+    assert!(is_task_started(get_task(&state_dynamic, &"b".to_string())));
+}
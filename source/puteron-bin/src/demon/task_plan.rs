@@ -5,15 +5,20 @@ use {
             TaskState_,
         },
         task_util::{
+            are_all_conflicting_tasks_stopped,
             are_all_downstream_tasks_stopped,
+            get_conflicting_tasks,
             is_task_on,
             is_task_stopped,
+            maybe_get_task,
         },
     },
     crate::demon::{
         state::TaskStateSpecific,
         task_util::{
             are_all_upstream_tasks_started,
+            control_reason,
+            does_task_require,
             get_task,
             is_task_started,
             walk_task_upstream,
@@ -22,7 +27,10 @@ use {
     chrono::Utc,
     puteron::interface::{
         base::TaskId,
-        ipc::ProcState,
+        ipc::{
+            ControlSource,
+            ProcState,
+        },
         task::DependencyType,
     },
     std::{
@@ -39,6 +47,8 @@ pub(crate) struct ExecutePlan {
     pub(crate) log_stopped: HashSet<TaskId>,
     pub(crate) start: HashSet<TaskId>,
     pub(crate) stop: HashSet<TaskId>,
+    // Tasks other than the root whose `direct_on` was cleared (ex: by a conflict)
+    pub(crate) turned_off: HashSet<TaskId>,
 }
 
 /// After state changes
@@ -58,17 +68,42 @@ pub(crate) fn plan_event_stopping(state_dynamic: &StateDynamic, plan: &mut Execu
 
     // Stop all downstream immediately
     let mut frontier = vec![];
-    frontier.extend(get_task(state_dynamic, task_id).downstream.borrow().keys().cloned());
+
+    fn push_downstream(state_dynamic: &StateDynamic, frontier: &mut Vec<TaskId>, task: &TaskState_) {
+        for (downstream_id, downstream_type) in task.downstream.borrow().iter() {
+            match downstream_type {
+                DependencyType::Strong => { },
+                DependencyType::Weak => { },
+                DependencyType::After => {
+                    // Not torn down
+                    continue;
+                },
+                DependencyType::Conflicts => {
+                    // Not dependent
+                    continue;
+                },
+            }
+            if !does_task_require(state_dynamic, get_task(state_dynamic, downstream_id), &task.id) {
+                // Satisfied by another task in an any-of group
+                continue;
+            }
+            frontier.push(downstream_id.clone());
+        }
+    }
+
+    push_downstream(state_dynamic, &mut frontier, get_task(state_dynamic, task_id));
     while let Some(upstream_id) = frontier.pop() {
         let upstream_task = get_task(state_dynamic, &upstream_id);
         plan_stop_one_task(state_dynamic, plan, &upstream_task);
-        frontier.extend(upstream_task.downstream.borrow().keys().cloned());
+        push_downstream(state_dynamic, &mut frontier, upstream_task);
     }
 }
 
 /// After state change
 pub(crate) fn plan_event_stopped(state_dynamic: &StateDynamic, plan: &mut ExecutePlan, task_id: &TaskId) {
     propagate_stop_upstream(state_dynamic, plan, task_id);
+    propagate_start_conflicting(state_dynamic, plan, task_id);
+    propagate_start_after(state_dynamic, plan, task_id);
 }
 
 /// Return true if started - downstream can be started now.
@@ -76,6 +111,9 @@ pub(crate) fn plan_start_one_task(state_dynamic: &StateDynamic, plan: &mut Execu
     if !are_all_upstream_tasks_started(&state_dynamic, task) {
         return false;
     }
+    if !are_all_conflicting_tasks_stopped(&state_dynamic, task) {
+        return false;
+    }
     if is_task_started(task) {
         return true;
     }
@@ -144,6 +182,7 @@ pub(crate) fn plan_set_task_direct_on(state_dynamic: &StateDynamic, plan: &mut E
         if was_on {
             return;
         }
+        plan_turn_off_conflicting(state_dynamic, plan, task);
 
         // Set transitive_on for strong deps, start leaves
         {
@@ -157,6 +196,12 @@ pub(crate) fn plan_set_task_direct_on(state_dynamic: &StateDynamic, plan: &mut E
                             DependencyType::Weak => {
                                 continue;
                             },
+                            DependencyType::After => {
+                                continue;
+                            },
+                            DependencyType::Conflicts => {
+                                continue;
+                            },
                         }
                         frontier.push((true, upstream_id.clone()));
                     }
@@ -172,6 +217,7 @@ pub(crate) fn plan_set_task_direct_on(state_dynamic: &StateDynamic, plan: &mut E
                     if was_on {
                         continue;
                     }
+                    plan_turn_off_conflicting(state_dynamic, plan, upstream_task);
                     frontier.push((false, upstream_id));
                     push_frontier(&mut frontier, upstream_task);
                 } else {
@@ -212,11 +258,15 @@ pub(crate) fn plan_set_task_direct_off(state_dynamic: &StateDynamic, plan: &mut
     {
         let mut frontier = vec![];
         for (k, v) in task.downstream.borrow().iter() {
-            frontier.push((true, k.clone(), *v));
+            frontier.push((true, k.clone(), *v, task_id.clone()));
         }
-        while let Some((first_pass, downstream_id, downstream_type)) = frontier.pop() {
+        while let Some((first_pass, downstream_id, downstream_type, upstream_id)) = frontier.pop() {
             if first_pass {
                 let downstream_task = get_task(state_dynamic, &downstream_id);
+                if !does_task_require(state_dynamic, downstream_task, &upstream_id) {
+                    // Satisfied by another task in an any-of group
+                    continue;
+                }
                 match downstream_type {
                     DependencyType::Strong => {
                         if is_task_on(&downstream_task) {
@@ -224,12 +274,20 @@ pub(crate) fn plan_set_task_direct_off(state_dynamic: &StateDynamic, plan: &mut
                         }
                     },
                     DependencyType::Weak => { },
+                    DependencyType::After => {
+                        // Not torn down
+                        continue;
+                    },
+                    DependencyType::Conflicts => {
+                        // Not dependent
+                        continue;
+                    },
                 }
-                frontier.push((false, downstream_id.clone(), downstream_type));
+                frontier.push((false, downstream_id.clone(), downstream_type, upstream_id));
 
                 // Descend
                 for (k, v) in downstream_task.downstream.borrow().iter() {
-                    frontier.push((true, k.clone(), *v));
+                    frontier.push((true, k.clone(), *v, downstream_id.clone()));
                 }
             } else {
                 // Stop if possible
@@ -245,6 +303,7 @@ pub(crate) fn plan_set_task_direct_off(state_dynamic: &StateDynamic, plan: &mut
     // Stop upstream if this is already stopped
     if stopped {
         propagate_stop_upstream(state_dynamic, plan, task_id);
+        propagate_start_after(state_dynamic, plan, task_id);
     }
 }
 
@@ -260,6 +319,14 @@ pub(crate) fn propagate_transitive_off(state_dynamic: &StateDynamic, task_id: &T
                         // Hadn't started, so shouldn't stop
                         continue;
                     },
+                    DependencyType::After => {
+                        // Hadn't started, so shouldn't stop
+                        continue;
+                    },
+                    DependencyType::Conflicts => {
+                        // Not turned on by this task
+                        continue;
+                    },
                 }
                 frontier.push(up_id.clone());
             }
@@ -281,6 +348,14 @@ pub(crate) fn propagate_transitive_off(state_dynamic: &StateDynamic, task_id: &T
                     // Doesn't affect this task
                     continue;
                 },
+                DependencyType::After => {
+                    // Doesn't affect this task
+                    continue;
+                },
+                DependencyType::Conflicts => {
+                    // Doesn't affect this task
+                    continue;
+                },
             }
             if is_task_on(get_task(state_dynamic, downstream_id)) {
                 all_downstream_off = false;
@@ -306,7 +381,12 @@ fn propagate_start_downstream(state_dynamic: &StateDynamic, plan: &mut ExecutePl
     let mut frontier = vec![];
 
     fn push_downstream(frontier: &mut Vec<TaskId>, task: &TaskState_) {
-        frontier.extend(task.downstream.borrow().keys().cloned());
+        for (downstream_id, downstream_type) in task.downstream.borrow().iter() {
+            if *downstream_type == DependencyType::Conflicts {
+                continue;
+            }
+            frontier.push(downstream_id.clone());
+        }
     }
 
     push_downstream(&mut frontier, get_task(state_dynamic, from_task_id));
@@ -329,7 +409,10 @@ fn propagate_stop_upstream(state_dynamic: &StateDynamic, plan: &mut ExecutePlan,
 
     fn push_upstream(frontier: &mut Vec<TaskId>, task: &TaskState_) {
         walk_task_upstream(task, |upstream| {
-            for (up_id, _) in upstream {
+            for (up_id, up_type) in upstream {
+                if *up_type == DependencyType::Conflicts {
+                    continue;
+                }
                 frontier.push(up_id.clone());
             }
         });
@@ -344,6 +427,285 @@ fn propagate_stop_upstream(state_dynamic: &StateDynamic, plan: &mut ExecutePlan,
         if !plan_stop_one_task(state_dynamic, plan, &upstream_task) {
             continue;
         }
+        propagate_start_after(state_dynamic, plan, &upstream_id);
         push_upstream(&mut frontier, &upstream_task);
     }
 }
+
+/// The task if it's `direct_on`, plus `direct_on` tasks keeping it on via strong
+/// dependencies.
+fn get_direct_on_roots(state_dynamic: &StateDynamic, task_id: &TaskId) -> Vec<TaskId> {
+    let mut out = vec![];
+    let mut seen = HashSet::new();
+    let mut frontier = vec![task_id.clone()];
+    while let Some(id) = frontier.pop() {
+        if !seen.insert(id.clone()) {
+            continue;
+        }
+        let task = get_task(state_dynamic, &id);
+        if !is_task_on(task) {
+            continue;
+        }
+        if task.direct_on.get().0 {
+            out.push(id.clone());
+        }
+        for (downstream_id, downstream_type) in task.downstream.borrow().iter() {
+            if *downstream_type == DependencyType::Strong {
+                frontier.push(downstream_id.clone());
+            }
+        }
+    }
+    return out;
+}
+
+/// Turn off tasks that conflict with a task that was just turned on. The task
+/// won't start until they've stopped. If a conflicting task is only on because
+/// other tasks depend on it, those are turned off.
+fn plan_turn_off_conflicting(state_dynamic: &StateDynamic, plan: &mut ExecutePlan, task: &TaskState_) {
+    for other_id in get_conflicting_tasks(task) {
+        let Some(other) = maybe_get_task(state_dynamic, &other_id) else {
+            continue;
+        };
+        if !is_task_on(other) {
+            continue;
+        }
+        for root_id in get_direct_on_roots(state_dynamic, &other_id) {
+            if root_id == task.id {
+                continue;
+            }
+            *get_task(state_dynamic, &root_id).direct_on_reason.borrow_mut() =
+                Some(control_reason(ControlSource::Conflict));
+            plan.turned_off.insert(root_id.clone());
+            plan_set_task_direct_off(state_dynamic, plan, &root_id);
+        }
+    }
+}
+
+// When a task stops, start conflicting tasks that were waiting for it
+fn propagate_start_conflicting(state_dynamic: &StateDynamic, plan: &mut ExecutePlan, task_id: &TaskId) {
+    for other_id in get_conflicting_tasks(get_task(state_dynamic, task_id)) {
+        let Some(other) = maybe_get_task(state_dynamic, &other_id) else {
+            continue;
+        };
+        if !is_task_on(other) {
+            continue;
+        }
+        if !plan_start_one_task(state_dynamic, plan, other) {
+            continue;
+        }
+        propagate_start_downstream(state_dynamic, plan, &other_id);
+    }
+}
+
+// When a task that's off stops, start `after` dependents that were waiting for it
+fn propagate_start_after(state_dynamic: &StateDynamic, plan: &mut ExecutePlan, task_id: &TaskId) {
+    let task = get_task(state_dynamic, task_id);
+    if is_task_on(task) {
+        return;
+    }
+    let mut after = vec![];
+    for (downstream_id, downstream_type) in task.downstream.borrow().iter() {
+        if *downstream_type == DependencyType::After {
+            after.push(downstream_id.clone());
+        }
+    }
+    for downstream_id in after {
+        let downstream = get_task(state_dynamic, &downstream_id);
+        if !is_task_on(downstream) {
+            continue;
+        }
+        if !plan_start_one_task(state_dynamic, plan, downstream) {
+            continue;
+        }
+        propagate_start_downstream(state_dynamic, plan, &downstream_id);
+    }
+}
+
+/// Start a task if it's on, and then any downstream tasks waiting for it (ex: after
+/// it stopped for a restart).
+pub(crate) fn plan_start_if_on(state_dynamic: &StateDynamic, plan: &mut ExecutePlan, task_id: &TaskId) {
+    let task = get_task(state_dynamic, task_id);
+    if !is_task_on(task) {
+        return;
+    }
+    if !plan_start_one_task(state_dynamic, plan, task) {
+        return;
+    }
+    propagate_start_downstream(state_dynamic, plan, task_id);
+}
+
+/// Stop tasks that a replaced task used to depend on, if nothing else needs them
+/// any more.
+pub(crate) fn plan_stop_released_upstream(state_dynamic: &StateDynamic, plan: &mut ExecutePlan, upstream: Vec<TaskId>) {
+    for upstream_id in upstream {
+        let Some(upstream_task) = maybe_get_task(state_dynamic, &upstream_id) else {
+            continue;
+        };
+        if is_task_on(upstream_task) {
+            continue;
+        }
+        if !plan_stop_one_task(state_dynamic, plan, upstream_task) {
+            continue;
+        }
+        propagate_start_after(state_dynamic, plan, &upstream_id);
+        propagate_stop_upstream(state_dynamic, plan, &upstream_id);
+    }
+}
+
+/// Stop a task without changing its control state. Downstream tasks stop along with
+/// it (`plan_event_stopping`). Once it's stopped it's started again (see
+/// `event_stopped`) unless it's being replaced. Processless tasks stop immediately.
+pub(crate) fn plan_restart_task(state_dynamic: &StateDynamic, plan: &mut ExecutePlan, task_id: &TaskId) {
+    let task = get_task(state_dynamic, task_id);
+    match &task.specific {
+        TaskStateSpecific::Empty(specific) => {
+            if !specific.started.get().0 {
+                return;
+            }
+            plan_event_stopping(state_dynamic, plan, task_id);
+            specific.started.set((false, Utc::now()));
+            plan.log_stopped.insert(task_id.clone());
+            plan_event_stopped(state_dynamic, plan, task_id);
+        },
+        TaskStateSpecific::Long(specific) => {
+            match specific.state.get().0 {
+                ProcState::Stopped | ProcState::Stopping => {
+                    return;
+                },
+                ProcState::Starting | ProcState::Started => {
+                    plan.stop.insert(task_id.clone());
+                    return;
+                },
+            }
+        },
+        TaskStateSpecific::Short(specific) => {
+            match specific.state.get().0 {
+                ProcState::Stopped | ProcState::Stopping => {
+                    return;
+                },
+                ProcState::Starting => {
+                    plan.stop.insert(task_id.clone());
+                    return;
+                },
+                ProcState::Started => {
+                    // Already exited
+                    plan_event_stopping(state_dynamic, plan, task_id);
+                    specific.state.set((ProcState::Stopped, Utc::now()));
+                    plan.log_stopped.insert(task_id.clone());
+                    plan_event_stopped(state_dynamic, plan, task_id);
+                },
+            }
+        },
+    }
+    if !state_dynamic.replacements.contains_key(task_id) {
+        plan_start_if_on(state_dynamic, plan, task_id);
+    }
+}
+
+#[derive(Default, Debug)]
+pub(crate) struct DryRun {
+    pub(crate) plan: ExecutePlan,
+    /// Tasks other than the root whose `direct_on` would change
+    pub(crate) direct_on_changed: HashSet<TaskId>,
+    pub(crate) transitive_on_changed: HashSet<TaskId>,
+}
+
+fn merge_plan(dest: &mut ExecutePlan, source: ExecutePlan) {
+    dest.log_starting.extend(source.log_starting);
+    dest.log_started.extend(source.log_started);
+    dest.log_stopping.extend(source.log_stopping);
+    dest.log_stopped.extend(source.log_stopped);
+    dest.start.extend(source.start);
+    dest.stop.extend(source.stop);
+    dest.turned_off.extend(source.turned_off);
+}
+
+/// Plan turning a task on or off without executing anything. Processes that would
+/// be started or stopped are simulated as finishing (without failures or started
+/// actions), and planning repeats until nothing else changes, so the plan
+/// includes everything that cascades afterwards. Planning updates on flags and
+/// task states as it goes, so these are restored afterwards.
+pub(crate) fn plan_dry_run(state_dynamic: &StateDynamic, task_id: &TaskId, on: bool) -> DryRun {
+    let mut snapshot = vec![];
+    for task in state_dynamic.task_alloc.values() {
+        let state = match &task.specific {
+            TaskStateSpecific::Empty(s) => (Some(s.started.get()), None),
+            TaskStateSpecific::Long(s) => (None, Some(s.state.get())),
+            TaskStateSpecific::Short(s) => (None, Some(s.state.get())),
+        };
+        snapshot.push(
+            (task, task.direct_on.get(), task.direct_on_reason.borrow().clone(), task.transitive_on.get(), state),
+        );
+    }
+    let mut out = DryRun::default();
+    match on {
+        true => plan_set_task_direct_on(state_dynamic, &mut out.plan, task_id),
+        false => plan_set_task_direct_off(state_dynamic, &mut out.plan, task_id),
+    }
+
+    // Simulate processes finishing, each at most once per direction
+    let mut simulated_stop = HashSet::new();
+    let mut simulated_start = HashSet::new();
+    loop {
+        let mut pending_stop =
+            out.plan.stop.iter().filter(|id| !simulated_stop.contains(*id)).cloned().collect::<Vec<_>>();
+        let mut pending_start =
+            out.plan.start.iter().filter(|id| !simulated_start.contains(*id)).cloned().collect::<Vec<_>>();
+        if pending_stop.is_empty() && pending_start.is_empty() {
+            break;
+        }
+        pending_stop.sort();
+        pending_start.sort();
+        for id in pending_stop {
+            simulated_stop.insert(id.clone());
+            let task = get_task(state_dynamic, &id);
+            let proc_state = match &task.specific {
+                TaskStateSpecific::Empty(_) => continue,
+                TaskStateSpecific::Long(s) => &s.state,
+                TaskStateSpecific::Short(s) => &s.state,
+            };
+            let mut plan = ExecutePlan::default();
+            proc_state.set((ProcState::Stopping, Utc::now()));
+            plan_event_stopping(state_dynamic, &mut plan, &id);
+            proc_state.set((ProcState::Stopped, Utc::now()));
+            plan_event_stopped(state_dynamic, &mut plan, &id);
+            plan_start_if_on(state_dynamic, &mut plan, &id);
+            merge_plan(&mut out.plan, plan);
+        }
+        for id in pending_start {
+            simulated_start.insert(id.clone());
+            let task = get_task(state_dynamic, &id);
+            let proc_state = match &task.specific {
+                TaskStateSpecific::Empty(_) => continue,
+                TaskStateSpecific::Long(s) => &s.state,
+                TaskStateSpecific::Short(s) => &s.state,
+            };
+            if proc_state.get().0 != ProcState::Stopped || !is_task_on(task) {
+                continue;
+            }
+            let mut plan = ExecutePlan::default();
+            proc_state.set((ProcState::Starting, Utc::now()));
+            plan_event_starting(&mut plan, &id);
+            proc_state.set((ProcState::Started, Utc::now()));
+            plan_event_started(state_dynamic, &mut plan, &id);
+            merge_plan(&mut out.plan, plan);
+        }
+    }
+    for (task, direct_on, direct_on_reason, transitive_on, (started, proc_state)) in snapshot {
+        if task.id != *task_id && task.direct_on.get().0 != direct_on.0 {
+            out.direct_on_changed.insert(task.id.clone());
+        }
+        if task.transitive_on.get().0 != transitive_on.0 {
+            out.transitive_on_changed.insert(task.id.clone());
+        }
+        task.direct_on.set(direct_on);
+        *task.direct_on_reason.borrow_mut() = direct_on_reason;
+        task.transitive_on.set(transitive_on);
+        match &task.specific {
+            TaskStateSpecific::Empty(s) => s.started.set(started.unwrap()),
+            TaskStateSpecific::Long(s) => s.state.set(proc_state.unwrap()),
+            TaskStateSpecific::Short(s) => s.state.set(proc_state.unwrap()),
+        }
+    }
+    return out;
+}
@@ -8,6 +8,7 @@
 use {
     super::{
         state::{
+            State,
             StateDynamic,
             TaskStateEmpty,
             TaskStateLong,
@@ -16,12 +17,18 @@ use {
             TaskState_,
         },
         task_plan::{
+            plan_dry_run,
+            plan_event_stopping,
+            plan_restart_task,
             plan_set_task_direct_off,
             plan_set_task_direct_on,
+            plan_start_if_on,
+            plan_stop_released_upstream,
             ExecutePlan,
         },
         task_util::{
             get_task,
+            get_task_spec,
             walk_task_upstream,
         },
     },
@@ -36,16 +43,28 @@ use {
             Command,
             DependencyType,
             Environment,
+            Task,
             TaskSpecEmpty,
             TaskSpecLong,
             TaskSpecShort,
         },
     },
-    std::cell::{
-        Cell,
-        RefCell,
+    loga::Log,
+    std::{
+        cell::{
+            Cell,
+            RefCell,
+        },
+        path::PathBuf,
+        sync::{
+            Arc,
+            Mutex,
+        },
+    },
+    tokio::sync::{
+        oneshot,
+        Notify,
     },
-    tokio::sync::oneshot,
 };
 
 fn check<
@@ -60,12 +79,14 @@ fn check<
 ) {
     let mut errors = vec![];
     let expected_start = start.as_ref().iter().map(|x| x.to_string()).collect::<Vec<_>>();
-    let got_start = plan.start.iter().cloned().collect::<Vec<_>>();
+    let mut got_start = plan.start.iter().cloned().collect::<Vec<_>>();
+    got_start.sort();
     if got_start != expected_start {
         errors.push(format!("To start:\n  Expected: {:?}\n       Got: {:?}", expected_start, got_start));
     }
     let expected_stop = stop.as_ref().iter().map(|x| x.to_string()).collect::<Vec<_>>();
-    let got_stop = plan.stop.iter().cloned().collect::<Vec<_>>();
+    let mut got_stop = plan.stop.iter().cloned().collect::<Vec<_>>();
+    got_stop.sort();
     if got_stop != expected_stop {
         errors.push(format!("To stop:\n  Expected: {:?}\n       Got: {:?}", expected_stop, got_stop));
     }
@@ -92,6 +113,7 @@ fn task(id: &str, specific: TaskStateSpecific) -> TaskState_ {
             TaskStateSpecific::Long(s) => s.spec.default_on,
             TaskStateSpecific::Short(s) => s.spec.default_on,
         }, DateTime::UNIX_EPOCH)),
+        direct_on_reason: RefCell::new(None),
         transitive_on: Cell::new((false, DateTime::UNIX_EPOCH)),
         downstream: Default::default(),
         specific: specific,
@@ -100,7 +122,7 @@ fn task(id: &str, specific: TaskStateSpecific) -> TaskState_ {
     };
 }
 
-fn task_empty(
+pub(super) fn task_empty(
     id: &str,
     started: bool,
     upstream: impl IntoIterator<Item = (&'static str, DependencyType)>,
@@ -111,10 +133,20 @@ fn task_empty(
             _schema: Default::default(),
             default_on: started,
             upstream: upstream.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+            upstream_any: Default::default(),
+            schedule: Default::default(),
+            schedule_exclude: Default::default(),
+            allow_control: Default::default(),
+            labels: Default::default(),
         },
     }));
 }
 
+/// An empty task spec with the given upstream, for adding or replacing tasks.
+pub(super) fn spec(upstream: impl AsRef<[(&'static str, DependencyType)]>) -> Task {
+    return get_task_spec(&task_empty("x", false, upstream.as_ref().iter().cloned()));
+}
+
 fn task_short(
     id: &str,
     on: bool,
@@ -135,6 +167,7 @@ fn task_short(
         state: Cell::new((state, DateTime::UNIX_EPOCH)),
         pid: Default::default(),
         failed_start_count: Default::default(),
+        triggered: Default::default(),
         stop: match state {
             ProcState::Starting | ProcState::Started => RefCell::new(Some(oneshot::channel().0)),
             ProcState::Stopping | ProcState::Stopped => RefCell::new(None),
@@ -143,11 +176,20 @@ fn task_short(
             _schema: Default::default(),
             default_on: on,
             upstream: upstream.as_ref().into_iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+            upstream_any: Default::default(),
             schedule: Default::default(),
+            schedule_exclude: Default::default(),
+            allow_control: Default::default(),
+            labels: Default::default(),
             command: Command {
                 working_directory: Default::default(),
                 environment: Environment::default(),
                 line: Default::default(),
+                credentials: Default::default(),
+                runtime_directory: Default::default(),
+                state_directory: Default::default(),
+                cache_directory: Default::default(),
+                logs_directory: Default::default(),
             },
             success_codes: Default::default(),
             started_action: Default::default(),
@@ -185,25 +227,57 @@ fn task_long(
             _schema: Default::default(),
             default_on: on,
             upstream: upstream.as_ref().into_iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+            upstream_any: Default::default(),
+            schedule: Default::default(),
+            schedule_exclude: Default::default(),
+            allow_control: Default::default(),
+            labels: Default::default(),
             command: Command {
                 working_directory: Default::default(),
                 environment: Environment::default(),
                 line: Default::default(),
+                credentials: Default::default(),
+                runtime_directory: Default::default(),
+                state_directory: Default::default(),
+                cache_directory: Default::default(),
+                logs_directory: Default::default(),
             },
             started_check: Default::default(),
+            reload_signal: Default::default(),
+            reload_command: Default::default(),
+            reload_timeout: Default::default(),
             restart_delay: Default::default(),
             stop_timeout: Default::default(),
         },
     }));
 }
 
-fn build_state(tasks: impl IntoIterator<Item = TaskState_>) -> StateDynamic {
+fn with_upstream_any(
+    mut task: TaskState_,
+    group: &str,
+    upstream: impl AsRef<[(&'static str, DependencyType)]>,
+) -> TaskState_ {
+    let group_upstream = upstream.as_ref().into_iter().map(|(k, v)| (k.to_string(), *v)).collect();
+    match &mut task.specific {
+        TaskStateSpecific::Empty(s) => s.spec.upstream_any.insert(group.to_string(), group_upstream),
+        TaskStateSpecific::Long(s) => s.spec.upstream_any.insert(group.to_string(), group_upstream),
+        TaskStateSpecific::Short(s) => s.spec.upstream_any.insert(group.to_string(), group_upstream),
+    };
+    return task;
+}
+
+pub(super) fn build_state(tasks: impl IntoIterator<Item = TaskState_>) -> StateDynamic {
     let mut state_dynamic = StateDynamic {
         task_alloc: Default::default(),
         tasks: Default::default(),
         schedule_top: Default::default(),
         schedule: Default::default(),
         notify_reschedule: Default::default(),
+        maintenance_restore: Default::default(),
+        templates: Default::default(),
+        instances: Default::default(),
+        audit_log: Default::default(),
+        replacements: Default::default(),
     };
     for test_task in tasks.into_iter() {
         let id = test_task.id.clone();
@@ -241,6 +315,12 @@ fn build_state(tasks: impl IntoIterator<Item = TaskState_>) -> StateDynamic {
                         DependencyType::Weak => {
                             continue;
                         },
+                        DependencyType::After => {
+                            continue;
+                        },
+                        DependencyType::Conflicts => {
+                            continue;
+                        },
                     }
                 }
             })
@@ -258,6 +338,27 @@ fn build_state(tasks: impl IntoIterator<Item = TaskState_>) -> StateDynamic {
     return state_dynamic;
 }
 
+pub(super) fn build_test_state(state_dynamic: StateDynamic) -> Arc<State> {
+    return Arc::new(State {
+        log: Log::new_root(loga::INFO),
+        task_dirs: Default::default(),
+        env: Default::default(),
+        env_files: Default::default(),
+        maintenance_windows: Default::default(),
+        variables: Default::default(),
+        runtime_dir: PathBuf::from("/run"),
+        state_dir: PathBuf::from("/var/lib"),
+        cache_dir: PathBuf::from("/var/cache"),
+        logs_dir: PathBuf::from("/var/log"),
+        access: None,
+        user_ids: Default::default(),
+        group_ids: Default::default(),
+        dynamic: Mutex::new(state_dynamic),
+        notify_state: Notify::new(),
+        tokio_tasks: Default::default(),
+    });
+}
+
 #[test]
 fn single_on() {
     let state_dynamic = build_state([
@@ -458,3 +559,348 @@ fn stop_strong_upstream_weak_downstream_2() {
     plan_set_task_direct_off(&state_dynamic, &mut plan, &"b".to_string());
     check(&state_dynamic, plan, [], ["a"], [], ["b", "c"]);
 }
+
+#[test]
+fn conflict_on_stops_other() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", true, ProcState::Started, []),
+        task_empty("b", false, [("a", DependencyType::Conflicts)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_on(&state_dynamic, &mut plan, &"b".to_string());
+    check(&state_dynamic, plan, [], ["a"], [], ["b"]);
+    assert!(!get_task(&state_dynamic, &"a".to_string()).direct_on.get().0);
+}
+
+#[test]
+fn conflict_on_other_stops_immediately() {
+    let state_dynamic = build_state([
+        //. .
+        task_empty("a", true, []),
+        task_empty("b", false, [("a", DependencyType::Conflicts)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_on(&state_dynamic, &mut plan, &"b".to_string());
+    check(&state_dynamic, plan, [], [], ["b"], ["a"]);
+}
+
+#[test]
+fn conflict_on_reverse() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Stopped, []),
+        task_long("b", true, ProcState::Started, [("a", DependencyType::Conflicts)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_on(&state_dynamic, &mut plan, &"a".to_string());
+    check(&state_dynamic, plan, [], ["b"], [], ["a"]);
+}
+
+#[test]
+fn conflict_strong_upstream_stops_other() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", true, ProcState::Started, []),
+        task_empty("b", false, [("a", DependencyType::Conflicts)]),
+        task_empty("c", false, [("b", DependencyType::Strong)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_on(&state_dynamic, &mut plan, &"c".to_string());
+    check(&state_dynamic, plan, [], ["a"], [], ["b", "c"]);
+}
+
+#[test]
+fn conflict_transitive_turns_off_downstream() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Started, []),
+        task_long("c", true, ProcState::Started, [("a", DependencyType::Strong)]),
+        task_empty("b", false, [("a", DependencyType::Conflicts)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_on(&state_dynamic, &mut plan, &"b".to_string());
+    assert!(plan.turned_off.contains("c"));
+    check(&state_dynamic, plan, [], ["c"], [], ["b"]);
+    assert!(!get_task(&state_dynamic, &"c".to_string()).direct_on.get().0);
+    assert!(!get_task(&state_dynamic, &"a".to_string()).transitive_on.get().0);
+}
+
+#[test]
+fn conflict_off_doesnt_affect_other() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Stopped, []),
+        task_empty("b", true, [("a", DependencyType::Conflicts)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_off(&state_dynamic, &mut plan, &"b".to_string());
+    check(&state_dynamic, plan, [], [], [], ["a", "b"]);
+}
+
+#[test]
+fn after_waits_for_upstream() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", true, ProcState::Starting, []),
+        task_empty("b", false, [("a", DependencyType::After)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_on(&state_dynamic, &mut plan, &"b".to_string());
+    check(&state_dynamic, plan, [], [], [], ["b"]);
+}
+
+#[test]
+fn after_upstream_off() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Stopped, []),
+        task_empty("b", false, [("a", DependencyType::After)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_on(&state_dynamic, &mut plan, &"b".to_string());
+    check(&state_dynamic, plan, [], [], ["b"], ["a"]);
+}
+
+#[test]
+fn after_upstream_turned_off() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", true, ProcState::Stopped, []),
+        task_long("b", true, ProcState::Stopped, [("a", DependencyType::After)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_off(&state_dynamic, &mut plan, &"a".to_string());
+    check(&state_dynamic, plan, ["b"], [], [], ["a"]);
+}
+
+#[test]
+fn after_not_torn_down() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", true, ProcState::Started, []),
+        task_long("b", true, ProcState::Started, [("a", DependencyType::After)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_off(&state_dynamic, &mut plan, &"a".to_string());
+    check(&state_dynamic, plan, [], ["a"], ["b"], []);
+}
+
+#[test]
+fn any_start_one_started() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", true, ProcState::Started, []),
+        task_long("b", false, ProcState::Stopped, []),
+        with_upstream_any(
+            task_empty("c", false, []),
+            "ab",
+            [("a", DependencyType::Weak), ("b", DependencyType::Weak)],
+        ),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_on(&state_dynamic, &mut plan, &"c".to_string());
+    check(&state_dynamic, plan, [], [], ["c"], ["b"]);
+}
+
+#[test]
+fn any_start_strong_turns_on_all() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", true, ProcState::Started, []),
+        task_long("b", false, ProcState::Stopped, []),
+        with_upstream_any(
+            task_empty("c", false, []),
+            "ab",
+            [("a", DependencyType::Strong), ("b", DependencyType::Strong)],
+        ),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_on(&state_dynamic, &mut plan, &"c".to_string());
+    check(&state_dynamic, plan, ["b"], [], ["c"], []);
+}
+
+#[test]
+fn any_stop_one_of_started() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", true, ProcState::Started, []),
+        task_long("b", true, ProcState::Started, []),
+        with_upstream_any(
+            task_empty("c", true, []),
+            "ab",
+            [("a", DependencyType::Weak), ("b", DependencyType::Weak)],
+        ),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_off(&state_dynamic, &mut plan, &"b".to_string());
+    check(&state_dynamic, plan, [], ["b"], ["a", "c"], []);
+}
+
+#[test]
+fn any_stop_last_started() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Stopped, []),
+        task_long("b", true, ProcState::Started, []),
+        with_upstream_any(
+            task_empty("c", true, []),
+            "ab",
+            [("a", DependencyType::Weak), ("b", DependencyType::Weak)],
+        ),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_set_task_direct_off(&state_dynamic, &mut plan, &"b".to_string());
+    check(&state_dynamic, plan, [], ["b"], [], ["a", "c"]);
+}
+
+#[test]
+fn dry_run_restores() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Started, []),
+        task_empty("b", true, [("a", DependencyType::Strong)]),
+    ]);
+    let dry_run = plan_dry_run(&state_dynamic, &"b".to_string(), false);
+    assert!(dry_run.transitive_on_changed.contains("a"));
+    assert!(dry_run.direct_on_changed.is_empty());
+    check(&state_dynamic, dry_run.plan, [], ["a"], ["a", "b"], []);
+    assert!(get_task(&state_dynamic, &"a".to_string()).transitive_on.get().0);
+    assert!(get_task(&state_dynamic, &"b".to_string()).direct_on.get().0);
+}
+
+#[test]
+fn dry_run_cascades_after_stop() {
+    // `a` only stops once `b`'s process exits
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Started, []),
+        task_long("b", true, ProcState::Started, [("a", DependencyType::Strong)]),
+    ]);
+    let dry_run = plan_dry_run(&state_dynamic, &"b".to_string(), false);
+    check(&state_dynamic, dry_run.plan, [], ["a", "b"], ["a", "b"], []);
+}
+
+#[test]
+fn restart_proc() {
+    let state_dynamic = build_state([
+        //. .
+        task_empty("a", true, []),
+        task_long("b", true, ProcState::Started, [("a", DependencyType::Strong)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_restart_task(&state_dynamic, &mut plan, &"b".to_string());
+    check(&state_dynamic, plan, [], ["b"], ["a"], []);
+    assert!(get_task(&state_dynamic, &"b".to_string()).direct_on.get().0);
+}
+
+#[test]
+fn restart_empty_stops_downstream() {
+    let state_dynamic = build_state([
+        //. .
+        task_empty("a", true, []),
+        task_long("b", true, ProcState::Started, [("a", DependencyType::Strong)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_restart_task(&state_dynamic, &mut plan, &"a".to_string());
+    check(&state_dynamic, plan, [], ["b"], ["a"], []);
+    assert!(get_task(&state_dynamic, &"a".to_string()).transitive_on.get().0);
+}
+
+#[test]
+fn restart_off_noop() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Stopped, []),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_restart_task(&state_dynamic, &mut plan, &"a".to_string());
+    check(&state_dynamic, plan, [], [], [], ["a"]);
+}
+
+#[test]
+fn stopped_on_waits_for_upstream() {
+    // Stopped because the weak upstream stopped, but still on
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Stopping, []),
+        task_long("b", true, ProcState::Stopped, [("a", DependencyType::Weak)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_start_if_on(&state_dynamic, &mut plan, &"b".to_string());
+    check(&state_dynamic, plan, [], [], [], ["b"]);
+}
+
+#[test]
+fn stopped_on_upstream_restarted() {
+    // Upstream finished restarting before this finished stopping
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", true, ProcState::Started, []),
+        task_long("b", true, ProcState::Stopped, [("a", DependencyType::Strong)]),
+        task_long("c", true, ProcState::Stopped, [("b", DependencyType::Strong)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_start_if_on(&state_dynamic, &mut plan, &"b".to_string());
+    check(&state_dynamic, plan, ["b"], [], ["a"], ["c"]);
+}
+
+#[test]
+fn stopped_off_not_started() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Stopped, []),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_start_if_on(&state_dynamic, &mut plan, &"a".to_string());
+    check(&state_dynamic, plan, [], [], [], ["a"]);
+}
+
+#[test]
+fn released_upstream_stopped() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Started, []),
+        task_empty("b", false, [("a", DependencyType::Strong)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_stop_released_upstream(&state_dynamic, &mut plan, vec!["a".to_string()]);
+    check(&state_dynamic, plan, [], ["a"], [], ["b"]);
+}
+
+#[test]
+fn released_upstream_still_needed() {
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", false, ProcState::Started, []),
+        task_empty("c", true, [("a", DependencyType::Strong)]),
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_stop_released_upstream(&state_dynamic, &mut plan, vec!["a".to_string()]);
+    check(&state_dynamic, plan, [], [], ["a"], []);
+}
+
+#[test]
+fn any_of_member_stops_while_after_restarts() {
+    // `a` is restarting so `c`'s `after` edge isn't satisfied, but that shouldn't
+    // make `c` depend on `b1` when `b2` is still started
+    let mut c = task_long("c", true, ProcState::Started, [("a", DependencyType::After)]);
+    let TaskStateSpecific::Long(s) = &mut c.specific else {
+        unreachable!();
+    };
+    s.spec.upstream_any.insert("b".to_string(), [
+        //. .
+        ("b1".to_string(), DependencyType::Strong),
+        ("b2".to_string(), DependencyType::Strong),
+    ].into_iter().collect());
+    let state_dynamic = build_state([
+        //. .
+        task_long("a", true, ProcState::Starting, []),
+        task_long("b1", false, ProcState::Stopping, []),
+        task_long("b2", true, ProcState::Started, []),
+        c,
+    ]);
+    let mut plan = ExecutePlan::default();
+    plan_event_stopping(&state_dynamic, &mut plan, &"b1".to_string());
+    check(&state_dynamic, plan, [], [], ["b2", "c"], []);
+}
@@ -1,31 +1,251 @@
 use {
     super::state::{
+        State,
         StateDynamic,
+        TaskStateShort,
         TaskStateSpecific,
         TaskState_,
     },
+    crate::spec::substitute,
+    chrono::Utc,
+    loga::{
+        ea,
+        ResultContext,
+    },
     puteron::interface::{
         self,
-        base::TaskId,
+        base::{
+            AccessList,
+            TaskId,
+        },
         ipc::{
+            ControlReason,
+            ControlSource,
             ProcState,
         },
         task::{
+            schedule::{
+                Exclude,
+                Rule,
+            },
+            Command,
             DependencyType,
+            ManagedDirectory,
             ShortTaskStartedAction,
-            TaskSpecShort,
+            Task,
+        },
+    },
+    std::{
+        collections::HashMap,
+        path::{
+            Component,
+            Path,
+            PathBuf,
         },
     },
 };
 
+/// Variables provided by puteron, config `variables` can't use these names.
+pub(crate) const BUILTIN_VARIABLES: &[&str] = &["task_id", "runtime_dir", "state_dir", "cache_dir", "logs_dir"];
+
+/// Look up a variable referenced in a task spec: `env:NAME` for the environment
+/// passed to tasks (after the config's `environment` rules), builtins, then config
+/// `variables`.
+pub(crate) fn get_task_variable(state: &State, task_id: &TaskId, name: &str) -> Option<String> {
+    if let Some(name) = name.strip_prefix("env:") {
+        return state.env.get(name).cloned();
+    }
+    match name {
+        "task_id" => return Some(task_id.clone()),
+        "runtime_dir" => return Some(state.runtime_dir.to_string_lossy().to_string()),
+        "state_dir" => return Some(state.state_dir.to_string_lossy().to_string()),
+        "cache_dir" => return Some(state.cache_dir.to_string_lossy().to_string()),
+        "logs_dir" => return Some(state.logs_dir.to_string_lossy().to_string()),
+        _ => return state.variables.get(name).cloned(),
+    }
+}
+
+pub(crate) fn substitute_task_string(state: &State, task_id: &TaskId, s: &str) -> Result<String, loga::Error> {
+    return substitute(s, |name| get_task_variable(state, task_id, name));
+}
+
+pub(crate) fn substitute_task_path(state: &State, task_id: &TaskId, p: &Path) -> Result<PathBuf, loga::Error> {
+    let Some(s) = p.to_str() else {
+        // Not unicode, can't contain references
+        return Ok(p.to_path_buf());
+    };
+    return Ok(PathBuf::from(substitute_task_string(state, task_id, s)?));
+}
+
+/// The command's managed directories, with their base directory and the
+/// environment variable for passing the directory path to the command.
+pub(crate) fn get_managed_directories<'a>(
+    state: &'a State,
+    command: &'a Command,
+) -> Vec<(&'static str, &'a Path, &'a ManagedDirectory)> {
+    let mut out = vec![];
+    for (env_name, base, dir) in [
+        ("RUNTIME_DIRECTORY", &state.runtime_dir, &command.runtime_directory),
+        ("STATE_DIRECTORY", &state.state_dir, &command.state_directory),
+        ("CACHE_DIRECTORY", &state.cache_dir, &command.cache_directory),
+        ("LOGS_DIRECTORY", &state.logs_dir, &command.logs_directory),
+    ] {
+        if let Some(dir) = dir {
+            out.push((env_name, base.as_path(), dir));
+        }
+    }
+    return out;
+}
+
+/// Names in the runtime directory used by puteron itself (credentials, the ipc
+/// socket), which managed directories can't use.
+pub(crate) const RESERVED_RUNTIME_NAMES: &[&str] = &["puteron", "puteron.sock"];
+
+pub(crate) fn managed_directory_path(base: &Path, task_id: &TaskId, dir: &ManagedDirectory) -> PathBuf {
+    match &dir.name {
+        Some(name) => return base.join(name),
+        None => return base.join(task_id),
+    }
+}
+
+pub(crate) fn validate_managed_directory(dir: &ManagedDirectory) -> Result<u32, loga::Error> {
+    if let Some(name) = &dir.name {
+        if name.as_os_str().is_empty() || !name.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(
+                loga::err_with(
+                    "Directory name must be a relative path without `.` or `..`",
+                    ea!(name = name.to_string_lossy()),
+                ),
+            );
+        }
+    }
+    let Some(mode) = &dir.mode else {
+        return Ok(0o755);
+    };
+    let Some(mode) = u32::from_str_radix(mode, 8).ok().filter(|m| *m <= 0o7777) else {
+        return Err(loga::err_with("Directory mode must be octal permissions (ex: `0750`)", ea!(mode = mode)));
+    };
+    return Ok(mode);
+}
+
+/// Expand variables in the command line, working directory, added environment
+/// variables, environment file paths and credential source paths.
+pub(crate) fn substitute_task_command(
+    state: &State,
+    task_id: &TaskId,
+    command: &Command,
+) -> Result<Command, loga::Error> {
+    let mut command = command.clone();
+    for arg in &mut command.line {
+        *arg = substitute_task_string(state, task_id, arg).context("Error in command line")?;
+    }
+    if let Some(w) = &mut command.working_directory {
+        *w = substitute_task_path(state, task_id, w).context("Error in working directory")?;
+    }
+    for source in command.credentials.values_mut() {
+        *source = substitute_task_path(state, task_id, source).context("Error in credential source path")?;
+    }
+    for f in &mut command.environment.files {
+        f.path = substitute_task_path(state, task_id, &f.path).context("Error in environment file path")?;
+    }
+    for (k, v) in &mut command.environment.add {
+        *v =
+            substitute_task_string(
+                state,
+                task_id,
+                v,
+            ).context_with("Error in environment variable", ea!(variable = k))?;
+    }
+    return Ok(command);
+}
+
+/// Walk all upstream edges, including members of `upstream_any` groups.
 pub(crate) fn walk_task_upstream<
     'a,
     T,
 >(t: &'a TaskState_, mut cb: impl FnMut(&mut dyn Iterator<Item = (&'a TaskId, &'a DependencyType)>) -> T) -> T {
+    let (upstream, upstream_any) = get_task_upstream(t);
+    return cb(&mut upstream.iter().chain(upstream_any.values().flat_map(|g| g.iter())));
+}
+
+pub(crate) fn get_task_upstream(
+    t: &TaskState_,
+) -> (&HashMap<TaskId, DependencyType>, &HashMap<String, HashMap<TaskId, DependencyType>>) {
+    match &t.specific {
+        TaskStateSpecific::Empty(s) => return (&s.spec.upstream, &s.spec.upstream_any),
+        TaskStateSpecific::Long(s) => return (&s.spec.upstream, &s.spec.upstream_any),
+        TaskStateSpecific::Short(s) => return (&s.spec.upstream, &s.spec.upstream_any),
+    }
+}
+
+/// All upstream edges in a spec, including members of `upstream_any` groups.
+pub(crate) fn get_spec_upstream(spec: &Task) -> Vec<(&TaskId, &DependencyType)> {
+    let (upstream, upstream_any) = match spec {
+        Task::Empty(s) => (&s.upstream, &s.upstream_any),
+        Task::Long(s) => (&s.upstream, &s.upstream_any),
+        Task::Short(s) => (&s.upstream, &s.upstream_any),
+    };
+    return upstream.iter().chain(upstream_any.values().flat_map(|g| g.iter())).collect();
+}
+
+/// Upstream tasks in a spec (including group members) that don't exist.
+pub(crate) fn get_missing_upstream(state_dynamic: &StateDynamic, spec: &Task) -> Vec<TaskId> {
+    let mut out = vec![];
+    for (upstream_id, _) in get_spec_upstream(spec) {
+        if maybe_get_task(state_dynamic, upstream_id).is_none() {
+            out.push(upstream_id.clone());
+        }
+    }
+    out.sort();
+    return out;
+}
+
+pub(crate) fn get_spec_allow_control(spec: &Task) -> &AccessList {
+    match spec {
+        Task::Empty(s) => return &s.allow_control,
+        Task::Long(s) => return &s.allow_control,
+        Task::Short(s) => return &s.allow_control,
+    }
+}
+
+pub(crate) fn get_task_allow_control(t: &TaskState_) -> &AccessList {
     match &t.specific {
-        TaskStateSpecific::Empty(s) => return cb(&mut s.spec.upstream.iter()),
-        TaskStateSpecific::Long(s) => return cb(&mut s.spec.upstream.iter()),
-        TaskStateSpecific::Short(s) => return cb(&mut s.spec.upstream.iter()),
+        TaskStateSpecific::Empty(s) => return &s.spec.allow_control,
+        TaskStateSpecific::Long(s) => return &s.spec.allow_control,
+        TaskStateSpecific::Short(s) => return &s.spec.allow_control,
+    }
+}
+
+pub(crate) fn get_task_spec(t: &TaskState_) -> Task {
+    match &t.specific {
+        TaskStateSpecific::Empty(s) => return Task::Empty(s.spec.clone()),
+        TaskStateSpecific::Long(s) => return Task::Long(s.spec.clone()),
+        TaskStateSpecific::Short(s) => return Task::Short(s.spec.clone()),
+    }
+}
+
+pub(crate) fn is_same_task_spec(t: &TaskState_, spec: &Task) -> bool {
+    match (spec, &t.specific) {
+        (Task::Empty(new), TaskStateSpecific::Empty(old)) => return new == &old.spec,
+        (Task::Long(new), TaskStateSpecific::Long(old)) => return new == &old.spec,
+        (Task::Short(new), TaskStateSpecific::Short(old)) => return new == &old.spec,
+        _ => return false,
+    }
+}
+
+pub(crate) fn get_task_schedule(t: &TaskState_) -> (&[Rule], &[Exclude]) {
+    match &t.specific {
+        TaskStateSpecific::Empty(s) => return (&s.spec.schedule, &s.spec.schedule_exclude),
+        TaskStateSpecific::Long(s) => return (&s.spec.schedule, &s.spec.schedule_exclude),
+        TaskStateSpecific::Short(s) => return (&s.spec.schedule, &s.spec.schedule_exclude),
+    }
+}
+
+pub(crate) fn get_task_labels(t: &TaskState_) -> &[String] {
+    match &t.specific {
+        TaskStateSpecific::Empty(s) => return &s.spec.labels,
+        TaskStateSpecific::Long(s) => return &s.spec.labels,
+        TaskStateSpecific::Short(s) => return &s.spec.labels,
     }
 }
 
@@ -56,10 +276,34 @@ pub(crate) fn is_task_started(t: &TaskState_) -> bool {
     }
 }
 
-pub(crate) fn get_short_task_started_action(specific: &TaskSpecShort) -> ShortTaskStartedAction {
-    return match specific.started_action {
+/// A reason with only the source set, other fields can be filled in after.
+pub(crate) fn control_reason(source: ControlSource) -> ControlReason {
+    return ControlReason {
+        source: source,
+        at: Utc::now(),
+        uid: None,
+        pid: None,
+        rule: None,
+        maintenance_window: None,
+    };
+}
+
+/// The task state, with processless tasks either started or stopped.
+pub(crate) fn get_task_state(t: &TaskState_) -> ProcState {
+    match &t.specific {
+        TaskStateSpecific::Empty(s) => match s.started.get().0 {
+            true => return ProcState::Started,
+            false => return ProcState::Stopped,
+        },
+        TaskStateSpecific::Long(s) => return s.state.get().0,
+        TaskStateSpecific::Short(s) => return s.state.get().0,
+    }
+}
+
+pub(crate) fn get_short_task_started_action(specific: &TaskStateShort) -> ShortTaskStartedAction {
+    return match specific.spec.started_action {
         None => {
-            if specific.schedule.is_empty() {
+            if specific.spec.schedule.is_empty() && !specific.triggered.get() {
                 interface::task::ShortTaskStartedAction::None
             } else {
                 interface::task::ShortTaskStartedAction::TurnOff
@@ -69,21 +313,69 @@ pub(crate) fn get_short_task_started_action(specific: &TaskSpecShort) -> ShortTa
     };
 }
 
+pub(crate) fn is_upstream_satisfied(
+    state_dynamic: &StateDynamic,
+    task_id: &TaskId,
+    dep_type: DependencyType,
+    without: Option<&TaskId>,
+) -> bool {
+    let Some(dep) = maybe_get_task(state_dynamic, task_id) else {
+        match dep_type {
+            DependencyType::Strong | DependencyType::Weak | DependencyType::After => return false,
+            DependencyType::Conflicts => return true,
+        }
+    };
+    let started = is_task_started(dep) && without != Some(task_id);
+    match dep_type {
+        DependencyType::Strong | DependencyType::Weak => return started,
+        DependencyType::After => return started || !is_task_on(dep),
+        // Checked separately
+        DependencyType::Conflicts => return true,
+    }
+}
+
 pub(crate) fn are_all_upstream_tasks_started(state_dynamic: &StateDynamic, task: &TaskState_) -> bool {
-    let mut all_started = true;
-    walk_task_upstream(task, |deps| {
-        for (task_id, _) in deps {
-            let Some(dep) = state_dynamic.tasks.get(task_id) else {
-                all_started = false;
-                return;
-            };
-            if !is_task_started(&state_dynamic.task_alloc[*dep]) {
-                all_started = false;
-                return;
-            }
+    let (upstream, upstream_any) = get_task_upstream(task);
+    for (task_id, dep_type) in upstream {
+        if !is_upstream_satisfied(state_dynamic, task_id, *dep_type, None) {
+            return false;
         }
-    });
-    return all_started;
+    }
+    for group in upstream_any.values() {
+        if !group.iter().any(|(task_id, dep_type)| is_upstream_satisfied(state_dynamic, task_id, *dep_type, None)) {
+            return false;
+        }
+    }
+    return true;
+}
+
+/// Whether the downstream task would need to stop if the upstream task stopped
+/// (false if it's not a strong or weak dependency, or it's satisfied by another
+/// task in each `upstream_any` group it's in). Only the edges to `upstream_id` are
+/// considered, not the state of the downstream task's other upstream tasks.
+pub(crate) fn does_task_require(state_dynamic: &StateDynamic, downstream: &TaskState_, upstream_id: &TaskId) -> bool {
+    fn is_requiring(dep_type: DependencyType) -> bool {
+        match dep_type {
+            DependencyType::Strong | DependencyType::Weak => return true,
+            DependencyType::After | DependencyType::Conflicts => return false,
+        }
+    }
+
+    let (upstream, upstream_any) = get_task_upstream(downstream);
+    if upstream.get(upstream_id).is_some_and(|t| is_requiring(*t)) {
+        return true;
+    }
+    for group in upstream_any.values() {
+        if !group.get(upstream_id).is_some_and(|t| is_requiring(*t)) {
+            continue;
+        }
+        if !group
+            .iter()
+            .any(|(task_id, dep_type)| is_upstream_satisfied(state_dynamic, task_id, *dep_type, Some(upstream_id))) {
+            return true;
+        }
+    }
+    return false;
 }
 
 pub(crate) fn is_task_stopped(t: &TaskState_) -> bool {
@@ -99,8 +391,50 @@ pub(crate) fn is_task_stopped(t: &TaskState_) -> bool {
 }
 
 pub(crate) fn are_all_downstream_tasks_stopped(state_dynamic: &StateDynamic, task: &TaskState_) -> bool {
-    for (task_id, _) in task.downstream.borrow().iter() {
-        if !is_task_stopped(get_task(state_dynamic, task_id)) {
+    for (task_id, dep_type) in task.downstream.borrow().iter() {
+        match dep_type {
+            DependencyType::Strong | DependencyType::Weak => { },
+            DependencyType::After | DependencyType::Conflicts => {
+                // Don't need to stop first
+                continue;
+            },
+        }
+        let downstream = get_task(state_dynamic, task_id);
+        if !does_task_require(state_dynamic, downstream, &task.id) {
+            // Satisfied by another task in an any-of group
+            continue;
+        }
+        if !is_task_stopped(downstream) {
+            return false;
+        }
+    }
+    return true;
+}
+
+/// Tasks that conflict with this task, whichever side declared the conflict.
+pub(crate) fn get_conflicting_tasks(task: &TaskState_) -> Vec<TaskId> {
+    let mut out = vec![];
+    walk_task_upstream(task, |upstream| {
+        for (upstream_id, upstream_type) in upstream {
+            if *upstream_type == DependencyType::Conflicts {
+                out.push(upstream_id.clone());
+            }
+        }
+    });
+    for (downstream_id, downstream_type) in task.downstream.borrow().iter() {
+        if *downstream_type == DependencyType::Conflicts {
+            out.push(downstream_id.clone());
+        }
+    }
+    return out;
+}
+
+pub(crate) fn are_all_conflicting_tasks_stopped(state_dynamic: &StateDynamic, task: &TaskState_) -> bool {
+    for task_id in get_conflicting_tasks(task) {
+        let Some(other) = maybe_get_task(state_dynamic, &task_id) else {
+            continue;
+        };
+        if !is_task_stopped(other) {
             return false;
         }
     }
@@ -0,0 +1,139 @@
+use {
+    super::{
+        state::{
+            State,
+            TaskState_,
+        },
+        task_util::{
+            get_task_state,
+            maybe_get_task,
+        },
+    },
+    puteron::interface::{
+        base::TaskId,
+        ipc::{
+            IpcError,
+            ProcState,
+        },
+    },
+    std::{
+        collections::{
+            BTreeMap,
+            BTreeSet,
+            HashMap,
+            HashSet,
+        },
+        sync::Arc,
+        time::Duration,
+    },
+    tokio::{
+        pin,
+        select,
+        sync::oneshot::{
+            self,
+            error::TryRecvError,
+        },
+        time::{
+            sleep_until,
+            Instant,
+        },
+    },
+};
+
+/// Only `started` and `stopped` waits can be canceled, by the task being turned off
+/// or on first.
+fn add_waiter(task: &TaskState_, target: ProcState) -> Option<oneshot::Receiver<bool>> {
+    let (notify_tx, notify_rx) = oneshot::channel();
+    match target {
+        ProcState::Started => {
+            task.started_waiters.borrow_mut().push(notify_tx);
+        },
+        ProcState::Stopped => {
+            task.stopped_waiters.borrow_mut().push(notify_tx);
+        },
+        ProcState::Starting | ProcState::Stopping => {
+            return None;
+        },
+    }
+    return Some(notify_rx);
+}
+
+/// Wait until all (or any) of the tasks are in the target state. On timeout the
+/// error has the state of each task.
+pub(crate) async fn wait_tasks(
+    state: &Arc<State>,
+    tasks: Vec<TaskId>,
+    target: ProcState,
+    any: bool,
+    timeout: Option<Duration>,
+) -> Result<(), IpcError> {
+    if tasks.is_empty() {
+        return Err(IpcError::InvalidRequest { message: "No tasks to wait for".to_string() });
+    }
+    let tasks = tasks.into_iter().collect::<BTreeSet<_>>();
+    let deadline = timeout.map(|x| Instant::now() + x);
+    let mut waiters: HashMap<TaskId, oneshot::Receiver<bool>> = HashMap::new();
+    let mut canceled: HashSet<TaskId> = HashSet::new();
+    loop {
+        // Listen before checking so no changes are missed
+        let notified = state.notify_state.notified();
+        pin!(notified);
+        notified.as_mut().enable();
+        let mut states = BTreeMap::new();
+        let mut reached = 0;
+        {
+            let state_dynamic = state.dynamic.lock().unwrap();
+            for task_id in &tasks {
+                let Some(task) = maybe_get_task(&state_dynamic, task_id) else {
+                    return Err(IpcError::UnknownTask { task: task_id.clone() });
+                };
+                let task_state = get_task_state(task);
+                states.insert(task_id.clone(), task_state);
+                if task_state == target {
+                    reached += 1;
+                }
+                match waiters.get_mut(task_id).map(|x| x.try_recv()) {
+                    Some(Err(TryRecvError::Empty)) => { },
+                    Some(Ok(false)) => {
+                        waiters.remove(task_id);
+                        canceled.insert(task_id.clone());
+                    },
+                    None | Some(Ok(true)) | Some(Err(TryRecvError::Closed)) => {
+                        // Keep a waiter registered to see if the wait is canceled later
+                        if canceled.contains(task_id) {
+                            continue;
+                        }
+                        if let Some(notify_rx) = add_waiter(task, target) {
+                            waiters.insert(task_id.clone(), notify_rx);
+                        }
+                    },
+                }
+            }
+        }
+        if (any && reached > 0) || (!any && reached == tasks.len()) {
+            return Ok(());
+        }
+        if !canceled.is_empty() && (!any || canceled.len() == tasks.len()) {
+            let task = tasks.iter().find(|x| canceled.contains(*x)).unwrap().clone();
+            match target {
+                ProcState::Started => return Err(IpcError::StartCanceled { task: task }),
+                ProcState::Stopped => return Err(IpcError::StopCanceled { task: task }),
+                ProcState::Starting | ProcState::Stopping => unreachable!(),
+            }
+        }
+        match deadline {
+            Some(deadline) => {
+                select!{
+                    _ = notified => {
+                    },
+                    _ = sleep_until(deadline) => {
+                        return Err(IpcError::WaitTimeout { states: states });
+                    }
+                }
+            },
+            None => {
+                notified.await;
+            },
+        }
+    }
+}
@@ -1,7 +1,14 @@
 use {
+    loga::ResultContext,
     puteron::interface::ipc::{
         ipc,
         ipc_path,
+        IpcError,
+    },
+    std::os::fd::{
+        AsFd,
+        AsRawFd,
+        BorrowedFd,
     },
 };
 
@@ -9,6 +16,94 @@ pub async fn client() -> Result<ipc::Client, loga::Error> {
     return Ok(ipc::Client::new(ipc_path().unwrap()).await.map_err(loga::err)?);
 }
 
-pub async fn client_req<I: ipc::ReqTrait>(req: I) -> Result<I::Resp, loga::Error> {
-    return Ok(client().await?.send_req(req).await.map_err(loga::err)?);
+/// Errors from ipc requests. Errors returned by the demon are kept structured so
+/// callers can act on them.
+pub enum ClientError {
+    Ipc(IpcError),
+    Other(loga::Error),
+}
+
+impl From<loga::Error> for ClientError {
+    fn from(value: loga::Error) -> Self {
+        return ClientError::Other(value);
+    }
+}
+
+/// Convert an error from sending an ipc request.
+pub fn client_error(e: impl ToString) -> ClientError {
+    let message = e.to_string();
+    match decode_ipc_error(&message) {
+        Some(e) => return ClientError::Ipc(e),
+        None => return ClientError::Other(loga::err(message)),
+    }
+}
+
+pub async fn client_req<I: ipc::ReqTrait>(req: I) -> Result<I::Resp, ClientError> {
+    return Ok(client().await?.send_req(req).await.map_err(client_error)?);
+}
+
+pub fn encode_ipc_error(e: &IpcError) -> String {
+    return serde_json::to_string(e).unwrap();
+}
+
+/// Returns `None` if the message is a plain text error (ex: from the ipc library).
+pub fn decode_ipc_error(message: &str) -> Option<IpcError> {
+    return serde_json::from_str(message).ok();
+}
+
+#[derive(Clone)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: i32,
+    /// Supplementary groups.
+    pub groups: Vec<u32>,
+}
+
+/// Supplementary groups of the process that connected, as recorded by the kernel
+/// when the connection was made (`SO_PEERGROUPS`, Linux 4.13+).
+fn get_socket_peergroups(fd: BorrowedFd) -> Result<Vec<u32>, std::io::Error> {
+    let gid_size = std::mem::size_of::<libc::gid_t>();
+    let mut groups: Vec<libc::gid_t> = vec![0; 16];
+    loop {
+        let mut len = (groups.len() * gid_size) as libc::socklen_t;
+
+        // Safety: the buffer is `len` bytes long
+        let res = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERGROUPS,
+                groups.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        let count = len as usize / gid_size;
+        if res == 0 {
+            groups.truncate(count);
+            return Ok(groups);
+        }
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ERANGE) && count > groups.len() {
+            // `len` is now the size needed
+            groups.resize(count, 0);
+            continue;
+        }
+        return Err(e);
+    }
+}
+
+/// Get the credentials of the process on the other end of an ipc connection
+/// (`SO_PEERCRED` and `SO_PEERGROUPS`). These are fixed when the client connects,
+/// so they can't change if the client process exits or passes the socket on.
+pub fn peer_credentials(conn: &ipc::ServerConn) -> Result<PeerCredentials, loga::Error> {
+    let cred =
+        rustix::net::sockopt::get_socket_peercred(&conn.0).context("Error getting ipc client credentials")?;
+    let groups = get_socket_peergroups(conn.0.as_fd()).context("Error getting ipc client supplementary groups")?;
+    return Ok(PeerCredentials {
+        uid: cred.uid.as_raw(),
+        gid: cred.gid.as_raw(),
+        pid: cred.pid.as_raw_nonzero().get(),
+        groups: groups,
+    });
 }
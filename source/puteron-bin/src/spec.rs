@@ -6,7 +6,24 @@ use {
         Log,
         ResultContext,
     },
-    puteron::interface,
+    puteron::interface::{
+        self,
+        base::{
+            is_template_id,
+            split_instance_id,
+            TaskId,
+        },
+        ipc::{
+            GraphEdge,
+            GraphNode,
+            GraphNodeType,
+            RespTaskGraph,
+        },
+        task::{
+            Command,
+            Task,
+        },
+    },
     std::{
         collections::{
             BTreeMap,
@@ -18,6 +35,147 @@ use {
     tokio::fs::read_dir,
 };
 
+fn merge_json(lower: serde_json::Value, upper: serde_json::Value) -> serde_json::Value {
+    match (lower, upper) {
+        (serde_json::Value::Object(mut lower), serde_json::Value::Object(upper)) => {
+            for (k, mut upper_child) in upper {
+                if let Some(lower_child) = lower.remove(&k) {
+                    upper_child = merge_json(lower_child, upper_child);
+                }
+                lower.insert(k, upper_child);
+            }
+            return serde_json::Value::Object(lower);
+        },
+        (_, upper) => {
+            return upper;
+        },
+    }
+}
+
+enum Segment<'a> {
+    Text(&'a str),
+    // `$$`
+    Escape,
+    // `${name}`
+    Var(&'a str),
+}
+
+fn parse_substitutions(s: &str) -> Result<Vec<Segment<'_>>, loga::Error> {
+    let mut out = vec![];
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        let after = &rest[i + 1..];
+        if after.starts_with('$') {
+            out.push(Segment::Text(&rest[..i]));
+            out.push(Segment::Escape);
+            rest = &after[1..];
+        } else if after.starts_with('{') {
+            let Some(end) = after.find('}') else {
+                return Err(loga::err_with("Unterminated variable reference, missing `}`", ea!(text = s)));
+            };
+            let name = &after[1..end];
+            if name.is_empty() {
+                return Err(loga::err_with("Empty variable reference `${}`", ea!(text = s)));
+            }
+            out.push(Segment::Text(&rest[..i]));
+            out.push(Segment::Var(name));
+            rest = &after[end + 1..];
+        } else {
+            // Lone `$`, literal
+            out.push(Segment::Text(&rest[..i + 1]));
+            rest = after;
+        }
+    }
+    out.push(Segment::Text(rest));
+    return Ok(out);
+}
+
+/// Expand variable references in a string from a task spec. `${name}` is replaced
+/// with the value of variable `name` (erroring if `lookup` doesn't know it) and `$$`
+/// is replaced with a single `$`. A `$` followed by anything else is left as is.
+pub fn substitute(s: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, loga::Error> {
+    let mut out = String::new();
+    for segment in parse_substitutions(s)? {
+        match segment {
+            Segment::Text(t) => out.push_str(t),
+            Segment::Escape => out.push('$'),
+            Segment::Var(name) => {
+                let Some(value) = lookup(name) else {
+                    return Err(loga::err_with("Undefined variable", ea!(variable = name, text = s)));
+                };
+                out.push_str(&value);
+            },
+        }
+    }
+    return Ok(out);
+}
+
+/// Replace `${instance}` in the command line, environment, working directory and
+/// upstream task ids. Other references and escapes are left for `substitute`.
+pub fn instantiate_template(spec: &Task, instance: &str) -> Task {
+    let sub = |s: &str| {
+        let Ok(segments) = parse_substitutions(s) else {
+            // Left as is, the error will come up when validating the task
+            return s.to_string();
+        };
+        let mut out = String::new();
+        for segment in segments {
+            match segment {
+                Segment::Text(t) => out.push_str(t),
+                Segment::Escape => out.push_str("$$"),
+                Segment::Var("instance") => out.push_str(instance),
+                Segment::Var(name) => {
+                    out.push_str("${");
+                    out.push_str(name);
+                    out.push_str("}");
+                },
+            }
+        }
+        return out;
+    };
+    let sub_upstream = |upstream: &mut HashMap<String, interface::task::DependencyType>| {
+        let new_upstream = upstream.drain().map(|(k, v)| (sub(k.as_str()), v)).collect();
+        *upstream = new_upstream;
+    };
+    let sub_command = |command: &mut Command| {
+        for arg in &mut command.line {
+            *arg = sub(arg.as_str());
+        }
+        for v in command.environment.add.values_mut() {
+            *v = sub(v.as_str());
+        }
+        if let Some(w) = &mut command.working_directory {
+            *w = PathBuf::from(sub(w.to_string_lossy().as_ref()));
+        }
+    };
+    let mut spec = spec.clone();
+    match &mut spec {
+        Task::Empty(s) => {
+            sub_upstream(&mut s.upstream);
+            for group in s.upstream_any.values_mut() {
+                sub_upstream(group);
+            }
+        },
+        Task::Long(s) => {
+            sub_upstream(&mut s.upstream);
+            for group in s.upstream_any.values_mut() {
+                sub_upstream(group);
+            }
+            sub_command(&mut s.command);
+        },
+        Task::Short(s) => {
+            sub_upstream(&mut s.upstream);
+            for group in s.upstream_any.values_mut() {
+                sub_upstream(group);
+            }
+            sub_command(&mut s.command);
+        },
+    }
+    return spec;
+}
+
+/// Templates (`worker@.json`) are returned like other tasks. Instances with files
+/// (`worker@eu1.json`) are merged on top of the template and instantiated.
 pub async fn merge_specs(
     log: &Log,
     dirs: &[PathBuf],
@@ -50,7 +208,8 @@ pub async fn merge_specs(
                     path.file_stem().unwrap().as_encoded_bytes().to_vec(),
                 ).context_with("Task directory entry has invalid unicode name", ea!(path = path.to_string_lossy()))?;
             if let Some(filter) = filter {
-                if task_name != filter {
+                if task_name != filter &&
+                    split_instance_id(filter).filter(|(template_id, _)| *template_id == task_name).is_none() {
                     continue;
                 }
             }
@@ -61,31 +220,36 @@ pub async fn merge_specs(
                     ).context_with("Error reading json from task directory", ea!(path = path.to_string_lossy()))?,
                 ).context_with("Task definition has invalid json", ea!(path = path.to_string_lossy()))?;
             if let Some(lower) = task_json.remove(&task_name) {
-                fn merge(lower: serde_json::Value, upper: serde_json::Value) -> serde_json::Value {
-                    match (lower, upper) {
-                        (serde_json::Value::Object(mut lower), serde_json::Value::Object(upper)) => {
-                            for (k, mut upper_child) in upper {
-                                if let Some(lower_child) = lower.remove(&k) {
-                                    upper_child = merge(lower_child, upper_child);
-                                }
-                                lower.insert(k, upper_child);
-                            }
-                            return serde_json::Value::Object(lower);
-                        },
-                        (_, upper) => {
-                            return upper;
-                        },
-                    }
-                }
-
-                config = merge(lower, config);
+                config = merge_json(lower, config);
             }
             task_json.insert(task_name, config);
         }
     }
+    let task_json_templates =
+        task_json
+            .iter()
+            .filter(|(id, _)| is_template_id(id))
+            .map(|(id, value)| (id.clone(), value.clone()))
+            .collect::<HashMap<_, _>>();
+    if let Some(filter) = filter {
+        if let Some((template_id, _)) = split_instance_id(filter) {
+            if !task_json.contains_key(filter) && task_json_templates.contains_key(&template_id) {
+                task_json.insert(filter.to_string(), serde_json::Value::Object(Default::default()));
+            }
+        }
+    }
+    for (id, value) in task_json.iter_mut() {
+        let Some((template_id, _)) = split_instance_id(id) else {
+            continue;
+        };
+        let Some(template) = task_json_templates.get(&template_id) else {
+            continue;
+        };
+        *value = merge_json(template.clone(), value.take());
+    }
     let mut tasks = BTreeMap::new();
     for (id, value) in task_json {
-        let config =
+        let mut config =
             serde_path_to_error::deserialize::<_, interface::task::Task>(
                 &mut serde_json::Deserializer::from_slice(
                     // https://github.com/serde-rs/json/issues/1233
@@ -95,7 +259,76 @@ pub async fn merge_specs(
                 "Task has invalid definition",
                 ea!(id = id, config = serde_json::to_string_pretty(&value).unwrap()),
             )?;
+        if let Some((template_id, instance)) = split_instance_id(&id) {
+            if task_json_templates.contains_key(&template_id) {
+                config = instantiate_template(&config, instance);
+            }
+        }
         tasks.insert(id, config);
     }
     return Ok(tasks);
 }
+
+/// Build the dependency graph from specs (ex: from `merge_specs`), creating
+/// instances of templates referenced as upstreams like the demon does. The nodes
+/// have no runtime state.
+pub fn build_spec_graph(specs: &BTreeMap<TaskId, Task>) -> RespTaskGraph {
+    let mut nodes = BTreeMap::new();
+    let mut edges = vec![];
+    let mut frontier = specs.keys().filter(|id| !is_template_id(id)).cloned().collect::<Vec<_>>();
+    while let Some(task_id) = frontier.pop() {
+        if nodes.contains_key(&task_id) {
+            continue;
+        }
+        let spec = match specs.get(&task_id) {
+            Some(spec) => spec.clone(),
+            None => {
+                let Some(spec) =
+                    split_instance_id(
+                        &task_id,
+                    ).and_then(
+                        |(template_id, instance)| specs
+                            .get(&template_id)
+                            .map(|template| instantiate_template(template, instance)),
+                    ) else {
+                        // Missing, shows up as an edge without a node
+                        continue;
+                    };
+                spec
+            },
+        };
+        let (task_type, upstream, upstream_any) = match &spec {
+            Task::Empty(s) => (GraphNodeType::Empty, &s.upstream, &s.upstream_any),
+            Task::Long(s) => (GraphNodeType::Long, &s.upstream, &s.upstream_any),
+            Task::Short(s) => (GraphNodeType::Short, &s.upstream, &s.upstream_any),
+        };
+        let upstream =
+            upstream
+                .iter()
+                .map(|(id, t)| (id, t, None))
+                .chain(
+                    upstream_any
+                        .iter()
+                        .flat_map(|(group, members)| members.iter().map(move |(id, t)| (id, t, Some(group)))),
+                );
+        for (upstream_id, dependency_type, group) in upstream {
+            edges.push(GraphEdge {
+                downstream: task_id.clone(),
+                upstream: upstream_id.clone(),
+                dependency_type: *dependency_type,
+                group: group.cloned(),
+            });
+            frontier.push(upstream_id.clone());
+        }
+        nodes.insert(task_id, GraphNode {
+            task_type: task_type,
+            on: None,
+            state: None,
+        });
+    }
+    edges.sort();
+    return RespTaskGraph {
+        nodes: nodes,
+        edges: edges,
+    };
+}
@@ -1 +1,44 @@
+use {
+    schemars::JsonSchema,
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+};
+
 pub type TaskId = String;
+
+/// Template task ids end with `@`. Instances of the template have ids with the
+/// instance name appended (ex: `worker@` and `worker@eu1`).
+pub fn is_template_id(id: &str) -> bool {
+    return id.ends_with('@');
+}
+
+/// If the id is of a template instance, returns the template id and the instance
+/// name.
+pub fn split_instance_id(id: &str) -> Option<(TaskId, &str)> {
+    let Some((prefix, instance)) = id.split_once('@') else {
+        return None;
+    };
+    if instance.is_empty() {
+        return None;
+    }
+    return Some((format!("{}@", prefix), instance));
+}
+
+/// A set of users and groups, matched against ipc clients' credentials. Groups
+/// are matched against the client process' primary and supplementary groups.
+#[derive(Serialize, Deserialize, Clone, JsonSchema, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct AccessList {
+    #[serde(default)]
+    pub uids: Vec<u32>,
+    #[serde(default)]
+    pub gids: Vec<u32>,
+    /// User names, resolved from `/etc/passwd` when the demon starts.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Group names, resolved from `/etc/group` when the demon starts.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
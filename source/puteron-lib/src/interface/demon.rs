@@ -1,4 +1,15 @@
 use {
+    super::{
+        base::{
+            AccessList,
+            TaskId,
+        },
+        task::EnvironmentFile,
+    },
+    chrono::{
+        DateTime,
+        Utc,
+    },
     schemars::JsonSchema,
     serde::{
         Deserialize,
@@ -24,6 +35,50 @@ pub struct Environment {
     /// Add or override the following environment variables;
     #[serde(default)]
     pub add: HashMap<String, String>,
+    /// Load environment variables from these files each time a task is started. These
+    /// override variables from `add`, and are subject to the task's `keep` filtering.
+    #[serde(default)]
+    pub files: Vec<EnvironmentFile>,
+}
+
+/// A period of time where scheduled tasks aren't turned on, for example during a
+/// holiday change freeze.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct MaintenanceWindow {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Skip scheduled turn-ons of these tasks during the window. If neither this nor
+    /// `suppress_schedule_labels` is specified, all scheduled turn-ons are skipped.
+    #[serde(default)]
+    pub suppress_schedule: Option<Vec<TaskId>>,
+    /// Skip scheduled turn-ons of tasks with any of these labels during the window.
+    #[serde(default)]
+    pub suppress_schedule_labels: Vec<String>,
+    /// Turn these tasks off when the window starts. Those that were on when the window
+    /// started are turned back on when it ends.
+    #[serde(default)]
+    pub turn_off: Vec<TaskId>,
+    /// Like `turn_off`, for tasks with any of these labels.
+    #[serde(default)]
+    pub turn_off_labels: Vec<String>,
+}
+
+/// Which ipc clients can make which requests. Root and the user the demon runs as
+/// can always make all requests.
+#[derive(Serialize, Deserialize, Clone, JsonSchema, Default)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Access {
+    /// Can query status, the schedule, plans, and wait for tasks.
+    #[serde(default)]
+    pub read: AccessList,
+    /// Can turn tasks on and off and run them now, plus everything in `read`.
+    #[serde(default)]
+    pub control: AccessList,
+    /// Can add and delete tasks and see task specs and the demon environment, plus
+    /// everything in `control`.
+    #[serde(default)]
+    pub manage: AccessList,
 }
 
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
@@ -35,4 +90,34 @@ pub struct Config {
     pub environment: Environment,
     #[serde(default)]
     pub task_dirs: Vec<PathBuf>,
+    /// Named maintenance windows.
+    #[serde(default)]
+    pub maintenance_windows: HashMap<String, MaintenanceWindow>,
+    /// Values for `${name}` references in task specs.
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    /// Base directory for runtime files, `${runtime_dir}` in task specs and the parent
+    /// of task `runtime_directory`s. Defaults to `XDG_RUNTIME_DIR` if set, otherwise
+    /// `/run`.
+    #[serde(default)]
+    pub runtime_dir: Option<PathBuf>,
+    /// Base directory for persistent files, `${state_dir}` in task specs and the
+    /// parent of task `state_directory`s. Defaults to `XDG_STATE_HOME` (or
+    /// `~/.local/state`) if `XDG_RUNTIME_DIR` is set, otherwise `/var/lib`.
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
+    /// Base directory for cache files, `${cache_dir}` in task specs and the parent of
+    /// task `cache_directory`s. Defaults to `XDG_CACHE_HOME` (or `~/.cache`) if
+    /// `XDG_RUNTIME_DIR` is set, otherwise `/var/cache`.
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+    /// Base directory for log files, `${logs_dir}` in task specs and the parent of
+    /// task `logs_directory`s. Defaults to `log` in the user state directory (see
+    /// above) if `XDG_RUNTIME_DIR` is set, otherwise `/var/log`.
+    #[serde(default)]
+    pub logs_dir: Option<PathBuf>,
+    /// Restrict ipc requests by client user and group. If not specified, anyone who
+    /// can open the ipc socket can make any request.
+    #[serde(default)]
+    pub access: Option<Access>,
 }
@@ -7,6 +7,7 @@ use {
             Task,
         },
     },
+    crate::time::SimpleDuration,
     chrono::{
         DateTime,
         Utc,
@@ -18,8 +19,12 @@ use {
         Serialize,
     },
     std::{
-        collections::HashMap,
+        collections::{
+            BTreeMap,
+            HashMap,
+        },
         env,
+        fmt::Display,
         path::PathBuf,
     },
 };
@@ -38,6 +43,156 @@ pub fn ipc_path() -> Option<PathBuf> {
     return Some(PathBuf::from("/run/puteron.sock"));
 }
 
+/// Errors returned by the demon. These are sent json-encoded as the ipc response
+/// error message. The variant name is the error code.
+#[derive(Serialize, Deserialize, Clone, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum IpcError {
+    /// No task (or template, for instances) exists with the id.
+    UnknownTask {
+        task: TaskId,
+    },
+    /// `unique` was set but a task or template with the id already exists.
+    TaskExists {
+        task: TaskId,
+    },
+    /// The request requires the task to be stopped (or, for run-now, off and
+    /// stopped).
+    TaskNotStopped {
+        task: TaskId,
+    },
+    /// The request only applies to short tasks.
+    NotShortTask {
+        task: TaskId,
+    },
+    /// The request requires the task's process to be running and started.
+    TaskNotStarted {
+        task: TaskId,
+    },
+    /// The task doesn't have a `reload_signal` or `reload_command`.
+    NotReloadable {
+        task: TaskId,
+    },
+    /// Sending the reload signal or running the reload command failed, or the process
+    /// exited while waiting for it to be started again.
+    ReloadFailed {
+        task: TaskId,
+        message: String,
+    },
+    /// The task spec (or a spec instantiated from a template) has errors.
+    InvalidSpec {
+        task: TaskId,
+        /// Upstream tasks that don't exist.
+        missing_upstream: Vec<TaskId>,
+        /// All errors, including missing upstream tasks.
+        errors: Vec<String>,
+    },
+    /// A batch request had errors, nothing was changed.
+    InvalidBatch {
+        /// Upstream tasks that don't exist after the batch.
+        missing_upstream: Vec<TaskId>,
+        /// All errors, including missing upstream tasks.
+        errors: Vec<String>,
+    },
+    /// The task was turned off before it started.
+    StartCanceled {
+        task: TaskId,
+    },
+    /// The task was turned on before it stopped.
+    StopCanceled {
+        task: TaskId,
+    },
+    PermissionDenied {
+        /// The demon-wide access the request requires (`read`, `control`, or
+        /// `manage`).
+        access: String,
+        /// For requests controlling a single task, the task whose `allow_control` was
+        /// also checked.
+        task: Option<TaskId>,
+    },
+    /// A wait request timed out before the tasks reached the state.
+    WaitTimeout {
+        /// The state of each task when the wait timed out.
+        states: BTreeMap<TaskId, ProcState>,
+    },
+    /// The request itself is malformed (ex: waiting on no tasks).
+    InvalidRequest {
+        message: String,
+    },
+    /// Anything else.
+    Internal {
+        message: String,
+    },
+}
+
+impl Display for IpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpcError::UnknownTask { task } => {
+                return format_args!("Unknown task [{}]", task).fmt(f);
+            },
+            IpcError::TaskExists { task } => {
+                return format_args!("A task or template with ID [{}] already exists", task).fmt(f);
+            },
+            IpcError::TaskNotStopped { task } => {
+                return format_args!("Task [{}] isn't stopped yet", task).fmt(f);
+            },
+            IpcError::NotShortTask { task } => {
+                return format_args!("Task [{}] isn't a short task", task).fmt(f);
+            },
+            IpcError::TaskNotStarted { task } => {
+                return format_args!("Task [{}] isn't started", task).fmt(f);
+            },
+            IpcError::NotReloadable { task } => {
+                return format_args!("Task [{}] has no reload signal or reload command", task).fmt(f);
+            },
+            IpcError::ReloadFailed { task, message } => {
+                return format_args!("Reloading task [{}] failed: {}", task, message).fmt(f);
+            },
+            IpcError::InvalidSpec { task, missing_upstream: _, errors } => {
+                format_args!("Task [{}] has errors:", task).fmt(f)?;
+                for e in errors {
+                    format_args!("\n- {}", e).fmt(f)?;
+                }
+                return Ok(());
+            },
+            IpcError::InvalidBatch { missing_upstream: _, errors } => {
+                "Batch has errors:".fmt(f)?;
+                for e in errors {
+                    format_args!("\n- {}", e).fmt(f)?;
+                }
+                return Ok(());
+            },
+            IpcError::StartCanceled { task } => {
+                return format_args!("Start of task [{}] canceled; task is now stopping", task).fmt(f);
+            },
+            IpcError::StopCanceled { task } => {
+                return format_args!("Stop of task [{}] canceled; task is now starting", task).fmt(f);
+            },
+            IpcError::PermissionDenied { access, task } => {
+                format_args!("Permission denied, this request requires {} access", access).fmt(f)?;
+                if let Some(task) = task {
+                    format_args!(" or control of task [{}]", task).fmt(f)?;
+                }
+                return Ok(());
+            },
+            IpcError::WaitTimeout { states } => {
+                "Timed out waiting, task states:".fmt(f)?;
+                for (task, state) in states {
+                    format_args!("\n- [{}] {}", task, state).fmt(f)?;
+                }
+                return Ok(());
+            },
+            IpcError::InvalidRequest { message } => {
+                return format_args!("Invalid request: {}", message).fmt(f);
+            },
+            IpcError::Internal { message } => {
+                return message.fmt(f);
+            },
+        }
+    }
+}
+
 // # Task
 //
 // List
@@ -53,6 +208,11 @@ pub struct RequestTaskAdd {
     pub spec: Task,
     /// Error if task already exists.
     pub unique: bool,
+    /// If the task exists and is running, stop it (and its downstream tasks), replace
+    /// the spec, and start everything again. The task's on state and downstream
+    /// edges are kept. Without this replacing a task that isn't stopped is an error.
+    #[serde(default)]
+    pub restart: bool,
 }
 
 // On/off
@@ -63,11 +223,62 @@ pub struct RequestTaskOnOff {
     pub on: bool,
 }
 
+// Run now
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RequestTaskRunNow {
+    pub task: TaskId,
+    /// Recalculate the next scheduled times for the task relative to now rather than
+    /// leaving the existing schedule as is.
+    #[serde(default)]
+    pub reset_schedule: bool,
+}
+
+// Restart
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RequestTaskRestart(pub TaskId);
+
+// Reload
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RequestTaskReload {
+    pub task: TaskId,
+    /// After reloading, wait for the task's `started_check` to pass before
+    /// responding. Processes usually keep their listener or check file through a
+    /// reload, so this often returns right away; it mostly helps with processes
+    /// that close or remove them while reloading. Fails with `reload_failed` if the
+    /// check doesn't pass within the task's `reload_timeout`.
+    #[serde(default)]
+    pub wait: bool,
+}
+
 // Delete
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct RequestTaskDelete(pub TaskId);
 
+// Batch
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum BatchOperation {
+    Add(RequestTaskAdd),
+    Delete(RequestTaskDelete),
+    OnOff(RequestTaskOnOff),
+}
+
+/// Apply multiple operations at once. Adds and deletes are validated together
+/// (so new tasks can depend on each other and deleted tasks can depend on each
+/// other), and if anything fails nothing is changed. On/off operations and
+/// turning on new `default_on` tasks happen after all adds and deletes. Each task
+/// can only appear in one add or delete and one on/off operation. Adds can't use
+/// `restart`.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RequestBatch {
+    pub operations: Vec<BatchOperation>,
+}
+
 // Status
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -80,7 +291,7 @@ pub struct TaskStatusSpecificEmpty {
     pub started_at: DateTime<Utc>,
 }
 
-#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub enum ProcState {
     Stopped,
@@ -89,6 +300,17 @@ pub enum ProcState {
     Stopping,
 }
 
+impl Display for ProcState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcState::Stopped => return "stopped".fmt(f),
+            ProcState::Starting => return "starting".fmt(f),
+            ProcState::Started => return "started".fmt(f),
+            ProcState::Stopping => return "stopping".fmt(f),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct TaskStatusSpecificLong {
@@ -122,9 +344,56 @@ pub struct TaskStatus {
     pub direct_on_at: DateTime<Utc>,
     pub transitive_on: bool,
     pub transitive_on_at: DateTime<Utc>,
+    /// What last set or cleared `direct_on`, if anything has.
+    #[serde(default)]
+    pub direct_on_reason: Option<ControlReason>,
     pub specific: TaskStatusSpecific,
 }
 
+/// What last turned a task on or off directly.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ControlSource {
+    /// Via ipc (ex: `puteron on`)
+    User,
+    /// `default_on` when the task was loaded
+    DefaultOn,
+    /// A schedule rule triggered or a schedule window started or ended
+    Schedule,
+    /// Via `run-now`
+    RunNow,
+    /// A maintenance window started or ended
+    Maintenance,
+    /// A short task's started action turned it off
+    StartedAction,
+    /// A conflicting task was turned on
+    Conflict,
+    /// The demon is shutting down
+    Shutdown,
+}
+
+/// A change to a task's `direct_on` and where it came from.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ControlReason {
+    pub source: ControlSource,
+    pub at: DateTime<Utc>,
+    /// The uid of the ipc client, if the change came over ipc and the peer
+    /// credentials were available.
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// The pid of the ipc client, like `uid`.
+    #[serde(default)]
+    pub pid: Option<i32>,
+    /// For `schedule`, the rule that triggered (not set for windows that were
+    /// already open at load).
+    #[serde(default)]
+    pub rule: Option<schedule::Rule>,
+    /// For `maintenance`, the maintenance window.
+    #[serde(default)]
+    pub maintenance_window: Option<String>,
+}
+
 // Get spec
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -140,6 +409,24 @@ pub struct RequestTaskWaitStarted(pub TaskId);
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct RequestTaskWaitStopped(pub TaskId);
 
+// Wait for state
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RequestTaskWait {
+    pub tasks: Vec<TaskId>,
+    /// Tasks without a process are only ever `started` or `stopped`. Waiting for
+    /// `started` fails if a task is turned off before starting, and waiting for
+    /// `stopped` fails if a task is turned on before stopping.
+    pub state: ProcState,
+    /// Return once any of the tasks is in the state, rather than all of them.
+    #[serde(default)]
+    pub any: bool,
+    /// Fail with `wait_timeout` if the tasks haven't reached the state after this
+    /// long.
+    #[serde(default)]
+    pub timeout: Option<SimpleDuration>,
+}
+
 // List user-on
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -164,6 +451,111 @@ pub struct RequestTaskListUpstream(pub TaskId);
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct RequestTaskListDownstream(pub TaskId);
 
+// Plan
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RequestTaskPlan {
+    pub task: TaskId,
+    pub on: bool,
+}
+
+/// What turning a task on or off would do, without doing it, including everything
+/// that would follow once the processes finish starting or stopping (assuming
+/// they succeed). All lists are sorted.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RespTaskPlan {
+    /// Processes that would be started.
+    pub start: Vec<TaskId>,
+    /// Processes that would be stopped.
+    pub stop: Vec<TaskId>,
+    /// Tasks without processes that would become started.
+    pub started: Vec<TaskId>,
+    /// Tasks without processes that would become stopped.
+    pub stopped: Vec<TaskId>,
+    /// Other tasks that would be turned off (ex: conflicting tasks).
+    pub turned_off: Vec<TaskId>,
+    /// Tasks that would become transitively on.
+    pub transitive_on: Vec<TaskId>,
+    /// Tasks that would no longer be transitively on.
+    pub transitive_off: Vec<TaskId>,
+}
+
+// Why
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RequestTaskWhy(pub TaskId);
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct WhyBlocker {
+    pub task: TaskId,
+    pub dependency_type: DependencyType,
+    /// The `upstream_any` group, if the task is one of the alternatives in a group.
+    pub group: Option<String>,
+    pub on: bool,
+    /// Not set if the task doesn't exist.
+    pub state: Option<ProcState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RespTaskWhy {
+    pub direct_on: bool,
+    /// What last set or cleared `direct_on`, if anything has.
+    pub direct_on_reason: Option<ControlReason>,
+    pub transitive_on: bool,
+    /// For each `direct_on` downstream task keeping this task on, the shortest chain
+    /// of `strong` dependencies from it to this task.
+    pub transitive_on_via: Vec<Vec<TaskId>>,
+    pub state: ProcState,
+    /// If the task is on but not started, the tasks preventing it from starting:
+    /// upstreams that aren't started and conflicting tasks that aren't stopped.
+    pub blocked_by: Vec<WhyBlocker>,
+}
+
+// Graph
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RequestTaskGraph;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum GraphNodeType {
+    Empty,
+    Long,
+    Short,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct GraphNode {
+    pub task_type: GraphNodeType,
+    /// Not set when the graph is built from specs rather than by the demon.
+    pub on: Option<bool>,
+    /// Not set when the graph is built from specs rather than by the demon. Tasks
+    /// without processes are only `started` or `stopped`.
+    pub state: Option<ProcState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct GraphEdge {
+    pub downstream: TaskId,
+    pub upstream: TaskId,
+    pub dependency_type: DependencyType,
+    /// The `upstream_any` group this edge is part of, if any.
+    pub group: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RespTaskGraph {
+    pub nodes: BTreeMap<TaskId, GraphNode>,
+    /// Sorted.
+    pub edges: Vec<GraphEdge>,
+}
+
 // # Demon
 //
 // Effective environment
@@ -174,7 +566,14 @@ pub struct RequestDemonEnv;
 // Schedule
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
-pub struct RequestDemonListSchedule;
+pub struct RequestDemonListSchedule {
+    /// Only list entries for this task.
+    #[serde(default)]
+    pub task: Option<TaskId>,
+    /// Return at most this many entries (the soonest).
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
 
 #[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -182,6 +581,29 @@ pub struct RespScheduleEntry {
     pub at: DateTime<Utc>,
     pub task: TaskId,
     pub rule: schedule::Rule,
+    /// Whether the task will be turned on or off (the end of a window)
+    pub on: bool,
+}
+
+// Audit log
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RequestDemonAuditLog {
+    /// Only list entries for this task.
+    #[serde(default)]
+    pub task: Option<TaskId>,
+    /// Return at most this many entries (the most recent).
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct AuditEntry {
+    pub task: TaskId,
+    /// The new `direct_on` value.
+    pub on: bool,
+    pub reason: ControlReason,
 }
 
 // Spec dirs
@@ -193,18 +615,27 @@ reqresp!(pub ipc {
     TaskList(RequestTaskList) => Vec < TaskId >,
     TaskAdd(RequestTaskAdd) =>(),
     TaskDelete(RequestTaskDelete) =>(),
+    Batch(RequestBatch) =>(),
     TaskGetStatus(RequestTaskGetStatus) => TaskStatus,
     TaskGetSpec(RequestTaskGetSpec) => Task,
     TaskOnOff(RequestTaskOnOff) =>(),
+    TaskRunNow(RequestTaskRunNow) =>(),
+    TaskRestart(RequestTaskRestart) =>(),
+    TaskReload(RequestTaskReload) =>(),
     TaskWaitStarted(RequestTaskWaitStarted) =>(),
     TaskWaitStopped(RequestTaskWaitStopped) =>(),
+    TaskWait(RequestTaskWait) =>(),
     TaskListUserOn(RequestTaskListUserOn) => Vec < TaskId >,
     TaskListUpstream(RequestTaskListUpstream) => HashMap < TaskId,
     TaskDependencyStatus >,
     TaskListDownstream(RequestTaskListDownstream) => HashMap < TaskId,
     TaskDependencyStatus >,
+    TaskPlan(RequestTaskPlan) => RespTaskPlan,
+    TaskWhy(RequestTaskWhy) => RespTaskWhy,
+    TaskGraph(RequestTaskGraph) => RespTaskGraph,
     DemonEnv(RequestDemonEnv) => HashMap < String,
     String >,
     DemonListSchedule(RequestDemonListSchedule) => Vec < RespScheduleEntry >,
+    DemonAuditLog(RequestDemonAuditLog) => Vec < AuditEntry >,
     DemonSpecDirs(RequestDemonSpecDirs) => Vec < PathBuf >,
 });
@@ -1,8 +1,12 @@
 use {
+    super::base::AccessList,
     crate::time::{
         SimpleDuration,
     },
-    schedule::Rule,
+    schedule::{
+        Exclude,
+        Rule,
+    },
     schemars::JsonSchema,
     serde::{
         Deserialize,
@@ -33,11 +37,28 @@ pub struct Environment {
     /// Add or override the following environment variables;
     #[serde(default)]
     pub add: HashMap<String, String>,
+    /// Load environment variables from these files when starting the task. These
+    /// override variables from `add`.
+    #[serde(default)]
+    pub files: Vec<EnvironmentFile>,
+}
+
+/// A file of `KEY=VALUE` lines (like a `.env` file). Blank lines and lines starting
+/// with `#` are ignored, and values may be surrounded with single quotes (taken
+/// literally) or double quotes (`\`, `\"` and `\n` escapes).
+#[derive(Serialize, Deserialize, Clone, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct EnvironmentFile {
+    pub path: PathBuf,
+    /// Skip the file if it doesn't exist, rather than failing to start.
+    #[serde(default)]
+    pub optional: bool,
 }
 
-/// All dependencies will prevent the dependent from starting until they've reached
-/// started state, and cause the dependent to stop when they leave started state.
-/// Additional behaviors are indicated in this struct.
+/// All dependencies (other than `conflicts`) will prevent the dependent from
+/// starting until they've reached started state, and cause the dependent to stop
+/// when they leave started state. Additional behaviors are indicated in this
+/// struct.
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub enum DependencyType {
@@ -45,6 +66,15 @@ pub enum DependencyType {
     /// on deps that are off).
     Strong,
     Weak,
+    /// Only orders startup: the dependent won't start until this has started or is
+    /// off. The dependent isn't stopped when this stops, and this doesn't wait for the
+    /// dependent to stop before stopping.
+    After,
+    /// Not a dependency: the two tasks can't run at the same time. When either task
+    /// is turned on the other is turned off, and the task waits for the other to stop
+    /// before starting. This applies in both directions, so only declare it on one of
+    /// the tasks.
+    Conflicts,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
@@ -56,9 +86,29 @@ pub struct TaskSpecEmpty {
     /// any dependency moves out of the started state this task will stop.
     #[serde(default)]
     pub upstream: HashMap<String, DependencyType>,
+    /// Named groups of alternative dependencies. At least one task in each group
+    /// must be started for this task to start, and this task only stops once every
+    /// task in a group has stopped. Only `strong` and `weak` dependencies are allowed
+    /// (`strong` members are all turned on with this task).
+    #[serde(default)]
+    pub upstream_any: HashMap<String, HashMap<String, DependencyType>>,
     /// Sets default on initially when the task is created (ex: at puteron start)
     #[serde(default)]
     pub default_on: bool,
+    /// Turn the task on and off on a schedule. Only `window` rules are allowed.
+    #[serde(default)]
+    pub schedule: Vec<Rule>,
+    /// Skip scheduled turn-ons that fall on any of these dates.
+    #[serde(default)]
+    pub schedule_exclude: Vec<Exclude>,
+    /// Users and groups allowed to turn this task on and off and restart it, in
+    /// addition to those with demon-wide `control` access.
+    #[serde(default)]
+    pub allow_control: AccessList,
+    /// Arbitrary labels for selecting groups of tasks, for example in maintenance
+    /// windows.
+    #[serde(default)]
+    pub labels: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
@@ -73,6 +123,49 @@ pub struct Command {
     /// The command line - program and arguments (as you'd pass to `execve`, so not
     /// implicitly executed by a shell).
     pub line: Vec<String>,
+    /// Files to make available to the command, by name. Before starting, each source
+    /// file is copied into a private directory (readable only by the user puteron
+    /// runs as), whose path is passed in the `CREDENTIALS_DIRECTORY` environment
+    /// variable. The directory is removed when the command exits.
+    #[serde(default)]
+    pub credentials: HashMap<String, PathBuf>,
+    /// Create a directory for the command in the demon's runtime base directory
+    /// (`/run` or `XDG_RUNTIME_DIR`), passed in the `RUNTIME_DIRECTORY` environment
+    /// variable.
+    #[serde(default)]
+    pub runtime_directory: Option<ManagedDirectory>,
+    /// Create a directory for the command in the demon's state base directory
+    /// (`/var/lib` or `XDG_STATE_HOME`), passed in the `STATE_DIRECTORY` environment
+    /// variable.
+    #[serde(default)]
+    pub state_directory: Option<ManagedDirectory>,
+    /// Create a directory for the command in the demon's cache base directory
+    /// (`/var/cache` or `XDG_CACHE_HOME`), passed in the `CACHE_DIRECTORY` environment
+    /// variable.
+    #[serde(default)]
+    pub cache_directory: Option<ManagedDirectory>,
+    /// Create a directory for the command in the demon's logs base directory
+    /// (`/var/log` or `XDG_STATE_HOME/log`), passed in the `LOGS_DIRECTORY`
+    /// environment variable.
+    #[serde(default)]
+    pub logs_directory: Option<ManagedDirectory>,
+}
+
+/// A directory created before the command starts, owned by the user puteron runs
+/// as.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ManagedDirectory {
+    /// Path relative to the base directory. Defaults to the task id.
+    #[serde(default)]
+    pub name: Option<PathBuf>,
+    /// Permissions of the directory (and of parent directories created for a nested
+    /// `name`), in octal (ex: `"0750"`). Defaults to `"0755"`.
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// Delete the directory and its contents when the task stops.
+    #[serde(default)]
+    pub remove_on_stop: bool,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
@@ -84,6 +177,17 @@ pub enum StartedCheck {
     Path(PathBuf),
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ReloadSignal {
+    Hup,
+    Int,
+    Quit,
+    Usr1,
+    Usr2,
+    Winch,
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct TaskSpecLong {
@@ -93,15 +197,48 @@ pub struct TaskSpecLong {
     /// any dependency moves out of the started state this task will stop.
     #[serde(default)]
     pub upstream: HashMap<String, DependencyType>,
+    /// Named groups of alternative dependencies. At least one task in each group
+    /// must be started for this task to start, and this task only stops once every
+    /// task in a group has stopped. Only `strong` and `weak` dependencies are allowed
+    /// (`strong` members are all turned on with this task).
+    #[serde(default)]
+    pub upstream_any: HashMap<String, HashMap<String, DependencyType>>,
     /// Sets default on initially when the task is created (ex: at puteron start)
     #[serde(default)]
     pub default_on: bool,
+    /// Turn the task on and off on a schedule. Only `window` rules are allowed.
+    #[serde(default)]
+    pub schedule: Vec<Rule>,
+    /// Skip scheduled turn-ons that fall on any of these dates.
+    #[serde(default)]
+    pub schedule_exclude: Vec<Exclude>,
+    /// Users and groups allowed to turn this task on and off, restart it and reload
+    /// it, in addition to those with demon-wide `control` access.
+    #[serde(default)]
+    pub allow_control: AccessList,
+    /// Arbitrary labels for selecting groups of tasks, for example in maintenance
+    /// windows.
+    #[serde(default)]
+    pub labels: Vec<String>,
     /// Command to run
     pub command: Command,
     /// How to determine if command has started - otherwise immediately transition to
     /// started from starting.
     #[serde(default)]
     pub started_check: Option<StartedCheck>,
+    /// Signal to send to the process for `puteron reload-task` (ex: `hup` to have it
+    /// re-read its config).
+    #[serde(default)]
+    pub reload_signal: Option<ReloadSignal>,
+    /// Command to run for `puteron reload-task`, instead of sending a signal. The
+    /// process's pid is passed in the `MAINPID` environment variable.
+    #[serde(default)]
+    pub reload_command: Option<Vec<String>>,
+    /// How long to wait for the reload command to finish, and with `--wait` for the
+    /// started check to pass again, before the reload is considered failed. Defaults
+    /// to 30s.
+    #[serde(default)]
+    pub reload_timeout: Option<SimpleDuration>,
     /// How long to wait between restarts when the command fails. Defaults to 60s.
     #[serde(default)]
     pub restart_delay: Option<SimpleDuration>,
@@ -133,12 +270,29 @@ pub struct TaskSpecShort {
     /// any dependency moves out of the started state this task will stop.
     #[serde(default)]
     pub upstream: HashMap<String, DependencyType>,
+    /// Named groups of alternative dependencies. At least one task in each group
+    /// must be started for this task to start, and this task only stops once every
+    /// task in a group has stopped. Only `strong` and `weak` dependencies are allowed
+    /// (`strong` members are all turned on with this task).
+    #[serde(default)]
+    pub upstream_any: HashMap<String, HashMap<String, DependencyType>>,
     /// Turn the task on as soon as it is loaded
     #[serde(default)]
     pub default_on: bool,
-    /// Turn the task on on a schedule
+    /// Turn the task on on a schedule. `window` rules also turn the task off.
     #[serde(default)]
     pub schedule: Vec<Rule>,
+    /// Skip scheduled turn-ons that fall on any of these dates.
+    #[serde(default)]
+    pub schedule_exclude: Vec<Exclude>,
+    /// Users and groups allowed to turn this task on and off, restart it and run it
+    /// now, in addition to those with demon-wide `control` access.
+    #[serde(default)]
+    pub allow_control: AccessList,
+    /// Arbitrary labels for selecting groups of tasks, for example in maintenance
+    /// windows.
+    #[serde(default)]
+    pub labels: Vec<String>,
     /// Command to run
     pub command: Command,
     /// Which exit codes are considered success.  By default, `0`.
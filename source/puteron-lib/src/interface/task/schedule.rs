@@ -5,6 +5,7 @@ use {
         SimpleDuration,
     },
     chrono::{
+        NaiveDate,
         NaiveTime,
         Weekday,
     },
@@ -13,6 +14,7 @@ use {
         Deserialize,
         Serialize,
     },
+    std::fmt::Display,
 };
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq, Debug)]
@@ -57,6 +59,18 @@ pub struct RuleYearly {
     pub time: NaiveTime,
 }
 
+/// Turn the task on at one time and off at another. For example, a `daily` `on`
+/// rule at `1:00` and a `daily` `off` rule at `6:00` keeps the task on between
+/// 1:00 and 6:00 every day.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct RuleWindow {
+    /// When the task is turned on. Can't be another window.
+    pub on: Box<Rule>,
+    /// When the task is turned off. Can't be another window.
+    pub off: Box<Rule>,
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq, Debug)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub enum Rule {
@@ -66,4 +80,57 @@ pub enum Rule {
     Weekly(RuleWeekly),
     Monthly(RuleMonthly),
     Yearly(RuleYearly),
+    Window(RuleWindow),
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct ExcludeDateRange {
+    /// First excluded date (inclusive)
+    pub start: NaiveDate,
+    /// Last excluded date (inclusive)
+    pub end: NaiveDate,
+}
+
+/// Dates when scheduled turn-ons are skipped. Like rules, dates are UTC.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Exclude {
+    DateRange(ExcludeDateRange),
+    // Lowercase, English (`monday`, `tuesday`, etc)
+    Weekday(Weekday),
+    Date(NaiveDate),
+}
+
+/// Human readable description. Times are UTC.
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rule::Period(r) => {
+                format_args!("every {}", r.period).fmt(f)?;
+                if r.scattered {
+                    " (scattered)".fmt(f)?;
+                }
+                return Ok(());
+            },
+            Rule::Hourly(r) => {
+                return format_args!("hourly at {}m{:02}s past the hour", r.minute, r.second).fmt(f);
+            },
+            Rule::Daily(r) => {
+                return format_args!("daily at {} UTC", r).fmt(f);
+            },
+            Rule::Weekly(r) => {
+                return format_args!("weekly on {} at {} UTC", r.weekday, r.time).fmt(f);
+            },
+            Rule::Monthly(r) => {
+                return format_args!("monthly on day {} at {} UTC", r.day, r.time).fmt(f);
+            },
+            Rule::Yearly(r) => {
+                return format_args!("yearly on {} {} at {} UTC", r.month.0.name(), r.day, r.time).fmt(f);
+            },
+            Rule::Window(r) => {
+                return format_args!("on {}, off {}", r.on, r.off).fmt(f);
+            },
+        }
+    }
 }
@@ -14,6 +14,7 @@ use {
     },
     std::{
         borrow::Cow,
+        fmt::Display,
         str::FromStr,
         time::Duration,
     },
@@ -48,42 +49,56 @@ pub const SUFFIX_SECOND: &str = "s";
 pub const SUFFIX_MINUTE: &str = "m";
 pub const SUFFIX_HOUR: &str = "h";
 
-impl Serialize for SimpleDuration {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer {
+impl Display for SimpleDuration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         return format_args!("{}{}", self.count, match self.unit {
             SimpleDurationUnit::Second => SUFFIX_SECOND,
             SimpleDurationUnit::Minute => SUFFIX_MINUTE,
             SimpleDurationUnit::Hour => SUFFIX_HOUR,
-        }).serialize(serializer);
+        }).fmt(f);
     }
 }
 
-impl<'de> Deserialize<'de> for SimpleDuration {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+impl Serialize for SimpleDuration {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
-        D: serde::Deserializer<'de> {
+        S: serde::Serializer {
+        return self.to_string().serialize(serializer);
+    }
+}
+
+impl FromStr for SimpleDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
         #[structre("(?<count>[0-9]+)(?<unit>[a-z]+)")]
         struct Parser<'a> {
             count: u64,
             unit: &'a str,
         }
 
-        let s = <Cow<str>>::deserialize(deserializer)?;
-        let p = Parser::try_from(s.as_ref()).map_err(|e| serde::de::Error::custom(e))?;
+        let p = Parser::try_from(s).map_err(|e| e.to_string())?;
         return Ok(Self {
             count: p.count,
             unit: match p.unit {
                 SUFFIX_SECOND => SimpleDurationUnit::Second,
                 SUFFIX_MINUTE => SimpleDurationUnit::Minute,
                 SUFFIX_HOUR => SimpleDurationUnit::Hour,
-                s => return Err(serde::de::Error::custom(format!("Unknown time unit suffix [{}]", s))),
+                s => return Err(format!("Unknown time unit suffix [{}]", s)),
             },
         });
     }
 }
 
+impl<'de> Deserialize<'de> for SimpleDuration {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de> {
+        let s = <Cow<str>>::deserialize(deserializer)?;
+        return Self::from_str(&s).map_err(|e| serde::de::Error::custom(e));
+    }
+}
+
 impl JsonSchema for SimpleDuration {
     fn schema_name() -> String {
         return "Duration".to_string();
@@ -125,6 +140,12 @@ impl JsonSchema for MinuteSecond {
     }
 }
 
+impl Display for MinuteSecond {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return format_args!("{:02}:{:02}", self.minute, self.second).fmt(f);
+    }
+}
+
 impl Serialize for MinuteSecond {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where